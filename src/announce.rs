@@ -0,0 +1,171 @@
+//! Listens for the announcements gateways multicast when their public address
+//! changes or they restart (RFC 6886 section 3.2.1, RFC 6887 section 14.1.1)
+
+use crate::events::{Change, Event};
+use crate::gateway::Gateway;
+use crate::transport::SERVER_PORT;
+use crate::{natpmp, pcp};
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{debug, info, warn};
+
+/// Port gateways send announcements to
+pub const CLIENT_PORT: u16 = 5350;
+
+/// All-hosts group the announcements are multicast to
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 1);
+
+/// Feeds the gateways' announcements into their cached address and epoch until an
+/// unrecoverable socket error. Gateways that move to another address are followed
+/// onto the interface that routes to it, as their resets show up in `events`.
+pub async fn listen(
+    gateways: Vec<Arc<Gateway>>,
+    events: impl Stream<Item = Result<Arc<Event>, u64>>,
+) {
+    let mut addresses = Vec::new();
+    for gateway in &gateways {
+        match gateway.address() {
+            IpAddr::V4(address) => addresses.push(address),
+            IpAddr::V6(_) => info!(
+                "Not listening for announcements from IPv6 gateway {}",
+                gateway
+            ),
+        }
+    }
+    if addresses.is_empty() {
+        return;
+    }
+
+    let mut interfaces = Vec::new();
+    let socket = match join(&addresses, &mut interfaces).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!(
+                "Failed to listen for gateway announcements on {}:{}: {}",
+                GROUP, CLIENT_PORT, e
+            );
+            return;
+        }
+    };
+
+    let mut events = std::pin::pin!(events);
+    let mut buf = [0u8; 1100];
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            Some(event) = events.next() => {
+                // Missed events may include a move, so check every gateway then
+                let moved: Vec<&Arc<Gateway>> = match event.as_deref() {
+                    Ok(Event {
+                        change: Change::GatewayReset { gateway },
+                        ..
+                    }) => gateways.iter().filter(|g| g.name == *gateway).collect(),
+                    Ok(_) => continue,
+                    Err(_) => gateways.iter().collect(),
+                };
+                for gateway in moved {
+                    let IpAddr::V4(address) = gateway.address() else {
+                        continue;
+                    };
+                    if let Err(e) = rejoin(&socket, address, &mut interfaces).await {
+                        warn!(
+                            "Failed to listen for announcements from gateway {}: {}",
+                            gateway, e
+                        );
+                    }
+                }
+                continue;
+            }
+        };
+        let (len, source) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Stopped listening for gateway announcements: {}", e);
+                return;
+            }
+        };
+        let senders: Vec<&Arc<Gateway>> = gateways
+            .iter()
+            .filter(|gateway| gateway.address() == source.ip())
+            .collect();
+        if senders.is_empty() {
+            debug!("Ignoring announcement from {}", source);
+            continue;
+        }
+
+        let packet = &buf[..len];
+        if let Some(announcement) = natpmp::parse_announcement(packet) {
+            for gateway in senders {
+                gateway.announced(announcement.epoch, Some(IpAddr::V4(announcement.address)));
+            }
+        } else if let Some(epoch) = pcp::parse_announcement(packet) {
+            for gateway in senders {
+                gateway.announced(epoch, None);
+            }
+        } else {
+            debug!("Ignoring unexpected announcement ({} bytes)", len);
+        }
+    }
+}
+
+/// Address of the interface that routes to the gateway
+async fn interface(gateway: Ipv4Addr) -> io::Result<Ipv4Addr> {
+    let route = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).await?;
+    route.connect((gateway, SERVER_PORT)).await?;
+    match route.local_addr()?.ip() {
+        IpAddr::V4(address) => Ok(address),
+        IpAddr::V6(_) => unreachable!("IPv4 socket has an IPv6 address"),
+    }
+}
+
+/// Joins the group on every interface that routes to one of the gateways, which is
+/// where their announcements arrive, and records the interfaces joined
+async fn join(gateways: &[Ipv4Addr], interfaces: &mut Vec<Ipv4Addr>) -> io::Result<UdpSocket> {
+    for &gateway in gateways {
+        let interface = interface(gateway).await?;
+        if !interfaces.contains(&interface) {
+            interfaces.push(interface);
+        }
+    }
+
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(socket2::Protocol::UDP))?;
+    // Other NAT-PMP clients on the host may be listening for the same announcements
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, CLIENT_PORT)).into())?;
+    for interface in interfaces.iter() {
+        socket.join_multicast_v4(&GROUP, interface)?;
+        info!(
+            "Listening for gateway announcements on {}:{} via {}",
+            GROUP, CLIENT_PORT, interface
+        );
+    }
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Joins the group on the interface that routes to a gateway's new address, unless
+/// it was joined already. Interfaces no gateway uses anymore stay joined, since
+/// announcements are told apart by their source anyway.
+async fn rejoin(
+    socket: &UdpSocket,
+    gateway: Ipv4Addr,
+    interfaces: &mut Vec<Ipv4Addr>,
+) -> io::Result<()> {
+    let interface = interface(gateway).await?;
+    if interfaces.contains(&interface) {
+        return Ok(());
+    }
+    socket.join_multicast_v4(GROUP, interface)?;
+    interfaces.push(interface);
+    info!(
+        "Listening for gateway announcements on {}:{} via {}",
+        GROUP, CLIENT_PORT, interface
+    );
+    Ok(())
+}
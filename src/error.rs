@@ -0,0 +1,114 @@
+//! Errors returned by the HTTP API, with a machine-readable code so clients can
+//! tell whether to retry, back off or give up
+
+use crate::gateway;
+use crate::{natpmp, pcp};
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+    code: &'static str,
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn unauthorized() -> Self {
+        ApiError::new(StatusCode::UNAUTHORIZED, "unauthorized", "Unauthorized")
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        ApiError::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.code
+    }
+
+    /// A failed gateway request, described as `context: error`
+    pub fn gateway(context: &str, error: &gateway::Error) -> Self {
+        let (status, code) = classify(error);
+        ApiError::new(status, code, format!("{}: {}", context, error))
+    }
+}
+
+/// The HTTP status and error code reported for a failed gateway request
+pub fn classify(error: &gateway::Error) -> (StatusCode, &'static str) {
+    match error {
+        gateway::Error::NatPmp(e) => natpmp_status(e),
+        gateway::Error::Pcp(e) => pcp_status(e),
+        gateway::Error::Unsupported(_) => (StatusCode::BAD_REQUEST, "unsupported_request"),
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorResponse {
+            error: self.message,
+            code: self.code,
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+fn natpmp_status(error: &natpmp::Error) -> (StatusCode, &'static str) {
+    use natpmp::ResultCode::*;
+    match error {
+        natpmp::Error::Io(_) => (StatusCode::SERVICE_UNAVAILABLE, "gateway_unreachable"),
+        natpmp::Error::Timeout => (StatusCode::GATEWAY_TIMEOUT, "timeout"),
+        natpmp::Error::Gateway(code) => match code {
+            UnsupportedVersion => (StatusCode::BAD_GATEWAY, "unsupported_version"),
+            NotAuthorized => (StatusCode::FORBIDDEN, "not_authorized"),
+            NetworkFailure => (StatusCode::SERVICE_UNAVAILABLE, "network_failure"),
+            OutOfResources => (StatusCode::INSUFFICIENT_STORAGE, "out_of_resources"),
+            UnsupportedOpcode => (StatusCode::NOT_IMPLEMENTED, "unsupported_opcode"),
+            Other(_) => (StatusCode::BAD_GATEWAY, "gateway_error"),
+        },
+    }
+}
+
+fn pcp_status(error: &pcp::Error) -> (StatusCode, &'static str) {
+    use pcp::ResultCode::*;
+    match error {
+        pcp::Error::Io(_) => (StatusCode::SERVICE_UNAVAILABLE, "gateway_unreachable"),
+        pcp::Error::Timeout => (StatusCode::GATEWAY_TIMEOUT, "timeout"),
+        pcp::Error::Gateway(code) => match code {
+            UnsupportedVersion => (StatusCode::BAD_GATEWAY, "unsupported_version"),
+            NotAuthorized => (StatusCode::FORBIDDEN, "not_authorized"),
+            MalformedRequest => (StatusCode::BAD_GATEWAY, "malformed_request"),
+            UnsupportedOpcode => (StatusCode::NOT_IMPLEMENTED, "unsupported_opcode"),
+            UnsupportedOption => (StatusCode::NOT_IMPLEMENTED, "unsupported_option"),
+            MalformedOption => (StatusCode::BAD_GATEWAY, "malformed_option"),
+            NetworkFailure => (StatusCode::SERVICE_UNAVAILABLE, "network_failure"),
+            NoResources => (StatusCode::INSUFFICIENT_STORAGE, "out_of_resources"),
+            UnsupportedProtocol => (StatusCode::NOT_IMPLEMENTED, "unsupported_protocol"),
+            UserExceededQuota => (StatusCode::TOO_MANY_REQUESTS, "quota_exceeded"),
+            CannotProvideExternal => (StatusCode::CONFLICT, "cannot_provide_external"),
+            AddressMismatch => (StatusCode::BAD_GATEWAY, "address_mismatch"),
+            ExcessiveRemotePeers => (StatusCode::TOO_MANY_REQUESTS, "excessive_remote_peers"),
+            Other(_) => (StatusCode::BAD_GATEWAY, "gateway_error"),
+        },
+    }
+}
@@ -0,0 +1,184 @@
+//! Changes to mappings and gateways, published to everyone watching /events
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::{broadcast, watch};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{BroadcastStream, WatchStream};
+use tokio_stream::{Stream, StreamExt};
+use tracing::debug;
+
+/// Events kept for subscribers that fall behind before they miss some
+const CAPACITY: usize = 256;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Change {
+    MappingCreated(MappingDetails),
+    MappingRenewed(MappingDetails),
+    ExternalPortChanged {
+        #[serde(flatten)]
+        mapping: MappingDetails,
+        previous_external_port: u16,
+    },
+    /// The mapping's lifetime ran out without a renewal
+    MappingExpired(MappingDetails),
+    /// The mapping was released, or dropped because it could not keep its port
+    MappingDeleted(MappingDetails),
+    ExternalAddressChanged {
+        gateway: String,
+        external_address: IpAddr,
+        previous_external_address: Option<IpAddr>,
+    },
+    /// The gateway lost its mappings; they are being re-created
+    GatewayReset {
+        gateway: String,
+    },
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MappingDetails {
+    /// Gateway or failover group the mapping was requested on
+    pub gateway: String,
+    /// Member of the failover group that holds the mapping
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub via: Option<String>,
+    pub protocol: String,
+    pub internal_port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub internal_address: Option<IpAddr>,
+    /// Remote peer of a PCP PEER mapping
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer: Option<SocketAddr>,
+    pub external_port: u16,
+    pub lifetime: u32,
+    pub expires_at: DateTime<Utc>,
+    /// Whether the server renews the mapping itself
+    pub managed: bool,
+}
+
+impl Change {
+    /// The `type` field, also used as the SSE event name
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Change::MappingCreated(_) => "mapping_created",
+            Change::MappingRenewed(_) => "mapping_renewed",
+            Change::ExternalPortChanged { .. } => "external_port_changed",
+            Change::MappingExpired(_) => "mapping_expired",
+            Change::MappingDeleted(_) => "mapping_deleted",
+            Change::ExternalAddressChanged { .. } => "external_address_changed",
+            Change::GatewayReset { .. } => "gateway_reset",
+        }
+    }
+
+    pub fn mapping(&self) -> Option<&MappingDetails> {
+        match self {
+            Change::MappingCreated(mapping)
+            | Change::MappingRenewed(mapping)
+            | Change::ExternalPortChanged { mapping, .. }
+            | Change::MappingExpired(mapping)
+            | Change::MappingDeleted(mapping) => Some(mapping),
+            Change::ExternalAddressChanged { .. } | Change::GatewayReset { .. } => None,
+        }
+    }
+
+    /// Names of the gateway (and failover group) the change happened on
+    fn gateways(&self) -> Vec<&str> {
+        match self {
+            Change::ExternalAddressChanged { gateway, .. } | Change::GatewayReset { gateway } => {
+                vec![gateway]
+            }
+            _ => {
+                let mapping = self.mapping().expect("Mapping change without a mapping");
+                let mut gateways = vec![mapping.gateway.as_str()];
+                gateways.extend(mapping.via.as_deref());
+                gateways
+            }
+        }
+    }
+}
+
+/// Selects the events a subscriber wants to see. Gateway-wide events concern every
+/// mapping, so only the gateway filter applies to them.
+#[derive(Default)]
+pub struct Filter {
+    pub internal_port: Option<u16>,
+    pub protocol: Option<String>,
+    /// Gateway or group names, any of which matches
+    pub gateways: Option<Vec<String>>,
+}
+
+impl Filter {
+    pub fn matches(&self, event: &Event) -> bool {
+        if let Some(gateways) = &self.gateways {
+            let on_gateway = event
+                .change
+                .gateways()
+                .iter()
+                .any(|name| gateways.iter().any(|gateway| gateway == name));
+            if !on_gateway {
+                return false;
+            }
+        }
+        let Some(mapping) = event.change.mapping() else {
+            return true;
+        };
+        self.internal_port
+            .is_none_or(|port| mapping.internal_port == port)
+            && self
+                .protocol
+                .as_ref()
+                .is_none_or(|protocol| mapping.protocol == *protocol)
+    }
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Arc<Event>>,
+    /// Set on shutdown, which ends every subscription
+    closed: Arc<watch::Sender<bool>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus {
+            sender: broadcast::channel(CAPACITY).0,
+            closed: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    pub fn publish(&self, change: Change) {
+        debug!("Event: {:?}", change);
+        // Nobody may be listening, which is fine
+        let _ = self.sender.send(Arc::new(Event {
+            timestamp: Utc::now(),
+            change,
+        }));
+    }
+
+    /// Events from now on, until the bus is closed. Subscribers that fall too far
+    /// behind get an error telling how many events they missed.
+    pub fn subscribe(&self) -> impl Stream<Item = Result<Arc<Event>, u64>> {
+        let events = BroadcastStream::new(self.sender.subscribe())
+            .map(|event| Some(event.map_err(|BroadcastStreamRecvError::Lagged(missed)| missed)));
+        let closed = WatchStream::from_changes(self.closed.subscribe()).map(|_| None);
+        events
+            .merge(closed)
+            .take_while(Option::is_some)
+            .map(|event| event.expect("Stream ended on close"))
+    }
+
+    /// Ends every subscription, so open connections do not hold up shutdown
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+}
@@ -0,0 +1,84 @@
+//! Groups of redundant gateways: mappings made through a group live on one member
+//! at a time and move to another when it stops answering
+
+use crate::gateway::Gateway;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::debug;
+
+/// A group given on the command line as `name=gateway,gateway,...`
+#[derive(Clone, Debug)]
+pub struct GroupSpec {
+    pub name: String,
+    /// Gateway names, in order of preference
+    pub members: Vec<String>,
+}
+
+impl FromStr for GroupSpec {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let Some((name, members)) = value.split_once('=') else {
+            return Err("expected name=gateway,gateway,...".to_string());
+        };
+        let members: Vec<String> = members
+            .split(',')
+            .map(|member| member.trim().to_string())
+            .filter(|member| !member.is_empty())
+            .collect();
+        if members.len() < 2 {
+            return Err(format!(
+                "failover group '{}' needs at least two gateways",
+                name
+            ));
+        }
+        Ok(GroupSpec {
+            name: name.to_string(),
+            members,
+        })
+    }
+}
+
+pub struct FailoverGroup {
+    pub name: String,
+    /// In order of preference
+    pub members: Vec<Arc<Gateway>>,
+    /// Member that holds the group's mappings
+    active: Mutex<Arc<Gateway>>,
+}
+
+impl FailoverGroup {
+    pub fn new(name: String, members: Vec<Arc<Gateway>>) -> Self {
+        let active = Mutex::new(members[0].clone());
+        FailoverGroup {
+            name,
+            members,
+            active,
+        }
+    }
+
+    pub fn active(&self) -> Arc<Gateway> {
+        self.active.lock().unwrap().clone()
+    }
+
+    pub fn set_active(&self, gateway: Arc<Gateway>) {
+        *self.active.lock().unwrap() = gateway;
+    }
+
+    /// Probes the other members in order of preference and returns the first one
+    /// that answers within `timeout`
+    pub async fn find_healthy(&self, timeout: Duration) -> Option<Arc<Gateway>> {
+        let active = self.active();
+        for member in &self.members {
+            if Arc::ptr_eq(member, &active) {
+                continue;
+            }
+            match member.probe_within(timeout).await {
+                Ok(_) => return Some(member.clone()),
+                Err(e) => debug!("Gateway {} is not healthy either: {}", member, e),
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,732 @@
+//! Talks to a VPN gateway over NAT-PMP or PCP behind a single interface
+
+use crate::events::{Change, EventBus};
+use crate::metrics::METRICS;
+use crate::{natpmp, pcp};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, Notify};
+use tracing::{debug, info, warn};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+}
+
+/// Port mapping protocol spoken to the gateway
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    Natpmp,
+    Pcp,
+    /// Try PCP first and fall back to NAT-PMP if the gateway does not support it
+    Auto,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Natpmp => write!(f, "NAT-PMP"),
+            Backend::Pcp => write!(f, "PCP"),
+            Backend::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Error {
+    NatPmp(natpmp::Error),
+    Pcp(pcp::Error),
+    /// The request cannot be expressed in the protocol spoken to the gateway
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NatPmp(e) => write!(f, "NAT-PMP: {}", e),
+            Error::Pcp(e) => write!(f, "PCP: {}", e),
+            Error::Unsupported(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<natpmp::Error> for Error {
+    fn from(e: natpmp::Error) -> Self {
+        Error::NatPmp(e)
+    }
+}
+
+impl From<pcp::Error> for Error {
+    fn from(e: pcp::Error) -> Self {
+        Error::Pcp(e)
+    }
+}
+
+/// A gateway given on the command line, as `name=address` or a bare address named `default`
+#[derive(Clone, Debug)]
+pub struct GatewaySpec {
+    pub name: String,
+    pub address: GatewayAddress,
+}
+
+#[derive(Clone, Debug)]
+pub enum GatewayAddress {
+    Fixed(IpAddr),
+    /// `auto`: looked up in the routing table, through the given interface (`auto%wg0`)
+    /// or the default route
+    Route(Option<String>),
+}
+
+impl FromStr for GatewaySpec {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, address) = value.split_once('=').unwrap_or(("default", value));
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid_name {
+            return Err(format!(
+                "invalid gateway name '{}' (use letters, digits, '-', '_' and '.')",
+                name
+            ));
+        }
+        let address = match address.split_once('%') {
+            _ if address == "auto" => GatewayAddress::Route(None),
+            Some(("auto", interface)) if !interface.is_empty() => {
+                GatewayAddress::Route(Some(interface.to_string()))
+            }
+            _ => GatewayAddress::Fixed(
+                address
+                    .parse()
+                    .map_err(|e| format!("invalid gateway address '{}': {}", address, e))?,
+            ),
+        };
+        Ok(GatewaySpec {
+            name: name.to_string(),
+            address,
+        })
+    }
+}
+
+/// A mapping to create, renew or (with a zero lifetime) delete
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Request {
+    pub protocol: Protocol,
+    pub internal_port: u16,
+    /// Host to map for instead of this server, e.g. to open an IPv6 pinhole (PCP only)
+    pub internal_address: Option<IpAddr>,
+    /// Remote peer for a PEER mapping instead of an inbound one (PCP only)
+    pub peer: Option<SocketAddr>,
+    /// Public port to ask the gateway for (0 = let the gateway choose)
+    pub external_port: u16,
+    pub lifetime: u32,
+}
+
+/// Result of a successful port mapping request
+#[derive(Clone)]
+pub struct Mapping {
+    pub external_port: u16,
+    pub lifetime: u32,
+    /// Seconds since the gateway's mapping table was initialized
+    pub epoch: u32,
+    /// Only reported by PCP
+    pub external_address: Option<IpAddr>,
+}
+
+/// Result of a successful reachability probe
+pub struct Status {
+    pub backend: Backend,
+    pub epoch: u32,
+    pub external_address: Option<IpAddr>,
+}
+
+/// Receives the outcome of a queued mapping request
+type Waiter = oneshot::Sender<Result<Mapping, Error>>;
+
+pub struct Gateway {
+    /// Identifies the gateway in requests, responses and metrics
+    pub name: String,
+    /// Changes when the gateway is looked up in the routing table and the route moves
+    address: Mutex<IpAddr>,
+    backend: Backend,
+    /// Protocol `auto` settled on after the gateway first answered
+    detected: Mutex<Option<Backend>>,
+    /// Last public address the gateway reported
+    external_address: Mutex<Option<IpAddr>>,
+    /// Last epoch the gateway reported and when it arrived
+    epoch: Mutex<Option<(u32, Instant)>>,
+    /// Signalled when the epoch shows that the gateway lost its mappings
+    reset: Notify,
+    /// Mapping requests queued or on the wire, with everyone waiting for their outcome
+    in_flight: Mutex<HashMap<Request, Vec<Waiter>>>,
+    /// Lets one request at a time talk to the gateway, in arrival order
+    queue: tokio::sync::Mutex<()>,
+    /// PCP requires renewals and deletes to carry the nonce the mapping was created
+    /// with; one nonce covers every mapping this server makes
+    nonce: [u8; 12],
+    events: EventBus,
+}
+
+impl Gateway {
+    /// Pass the nonce of a previous run to keep control of the PCP mappings it made
+    pub fn new(
+        name: String,
+        address: IpAddr,
+        backend: Backend,
+        nonce: Option<[u8; 12]>,
+        events: EventBus,
+    ) -> Self {
+        let nonce = nonce.unwrap_or_else(|| {
+            let mut nonce = [0u8; 12];
+            getrandom::fill(&mut nonce).expect("Failed to generate PCP nonce");
+            nonce
+        });
+
+        // NAT-PMP is IPv4 only, so there is nothing to detect for IPv6 gateways
+        let backend = match (address, backend) {
+            (IpAddr::V6(_), Backend::Auto) => Backend::Pcp,
+            (_, backend) => backend,
+        };
+
+        METRICS.add_gateway(&name);
+        Gateway {
+            name,
+            address: Mutex::new(address),
+            backend,
+            detected: Mutex::new(None),
+            external_address: Mutex::new(None),
+            epoch: Mutex::new(None),
+            reset: Notify::new(),
+            in_flight: Mutex::new(HashMap::new()),
+            queue: tokio::sync::Mutex::new(()),
+            nonce,
+            events,
+        }
+    }
+
+    pub fn address(&self) -> IpAddr {
+        *self.address.lock().unwrap()
+    }
+
+    /// Points the gateway at another server, e.g. after the VPN reconnected elsewhere.
+    /// Nothing learned from the previous one applies anymore, and since the new one
+    /// has none of our mappings this counts as a reset.
+    pub fn set_address(&self, address: IpAddr) {
+        let previous = std::mem::replace(&mut *self.address.lock().unwrap(), address);
+        if previous == address {
+            return;
+        }
+        warn!(
+            "Gateway {} moved from {} to {}",
+            self.name, previous, address
+        );
+        *self.detected.lock().unwrap() = None;
+        *self.epoch.lock().unwrap() = None;
+        if let Some(external_address) = self.external_address.lock().unwrap().take() {
+            METRICS.clear_external_address(&self.name, external_address);
+        }
+        self.events.publish(Change::GatewayReset {
+            gateway: self.name.clone(),
+        });
+        self.reset.notify_one();
+    }
+
+    /// The protocol in use: the configured one, or what `auto` detected so far
+    pub fn backend(&self) -> Backend {
+        match self.backend {
+            Backend::Auto => self.detected.lock().unwrap().unwrap_or(Backend::Auto),
+            backend => backend,
+        }
+    }
+
+    pub fn nonce(&self) -> [u8; 12] {
+        self.nonce
+    }
+
+    /// When the gateway last answered a request
+    pub fn last_response(&self) -> Option<Instant> {
+        self.epoch.lock().unwrap().map(|(_, at)| at)
+    }
+
+    /// The public address last reported by the gateway, if any
+    pub fn external_address(&self) -> Option<IpAddr> {
+        *self.external_address.lock().unwrap()
+    }
+
+    /// The cached public address, asking the gateway when nothing is known yet
+    pub async fn resolve_external_address(&self) -> Option<IpAddr> {
+        if let Some(address) = self.external_address() {
+            return Some(address);
+        }
+        match self.probe().await {
+            Ok(status) => status.external_address,
+            Err(e) => {
+                warn!("Failed to query public address of {}: {}", self, e);
+                None
+            }
+        }
+    }
+
+    /// Records an unsolicited announcement: a new public address (NAT-PMP only) and
+    /// an epoch that tells whether the gateway restarted
+    pub fn announced(&self, epoch: u32, external_address: Option<IpAddr>) {
+        if let Some(address) = external_address {
+            let previous = self.learned_address(address);
+            if previous != Some(address) {
+                match previous {
+                    Some(previous) => info!(
+                        "Gateway {} announced new public address: {} -> {}",
+                        self, previous, address
+                    ),
+                    None => info!("Gateway {} announced public address {}", self, address),
+                }
+            }
+        }
+        self.observe_epoch(epoch);
+    }
+
+    /// Waits until the gateway is found to have been reset
+    pub async fn reset(&self) {
+        self.reset.notified().await
+    }
+
+    /// Sends a mapping request over the protocol in use. Requests are sent one at a
+    /// time, and identical requests made while one is pending share its outcome.
+    pub async fn map(self: &Arc<Self>, request: &Request) -> Result<Mapping, Error> {
+        let (sender, receiver) = oneshot::channel();
+        let first = match self.in_flight.lock().unwrap().entry(request.clone()) {
+            Entry::Occupied(mut waiting) => {
+                waiting.get_mut().push(sender);
+                false
+            }
+            Entry::Vacant(slot) => {
+                slot.insert(vec![sender]);
+                true
+            }
+        };
+
+        if first {
+            // Runs on its own so the request completes even if the caller goes away
+            let gateway = self.clone();
+            let request = request.clone();
+            tokio::spawn(async move {
+                let result = {
+                    let _turn = gateway.queue.lock().await;
+                    gateway.map_now(&request).await
+                };
+                let waiting = gateway
+                    .in_flight
+                    .lock()
+                    .unwrap()
+                    .remove(&request)
+                    .unwrap_or_default();
+                if waiting.len() > 1 {
+                    debug!(
+                        "Answered {} identical requests with one round-trip",
+                        waiting.len()
+                    );
+                }
+                for sender in waiting {
+                    let _ = sender.send(result.clone());
+                }
+            });
+        }
+
+        receiver.await.expect("Gateway request task panicked")
+    }
+
+    async fn map_now(&self, request: &Request) -> Result<Mapping, Error> {
+        let mapping = self.timed("map", self.map_any(request)).await?;
+        if let Some(address) = mapping.external_address {
+            self.learned_address(address);
+        }
+        self.observe_epoch(mapping.epoch);
+        Ok(mapping)
+    }
+
+    async fn map_any(&self, request: &Request) -> Result<Mapping, Error> {
+        match self.backend() {
+            Backend::Natpmp => self.map_natpmp(request).await,
+            Backend::Pcp => self.map_pcp(request).await,
+            Backend::Auto => {
+                let result = self.map_pcp(request).await;
+                if self.fall_back(&result) {
+                    return self.map_natpmp(request).await;
+                }
+                result
+            }
+        }
+    }
+
+    /// Checks that the gateway answers, detecting the protocol when set to `auto`.
+    /// PCP has no way to ask for the public address, so the one learned from the
+    /// last mapping is reported instead.
+    pub async fn probe(&self) -> Result<Status, Error> {
+        let mut status = {
+            let _turn = self.queue.lock().await;
+            self.timed("probe", self.probe_any()).await?
+        };
+        match status.external_address {
+            Some(address) => {
+                self.learned_address(address);
+            }
+            None => status.external_address = self.external_address(),
+        }
+        self.observe_epoch(status.epoch);
+        Ok(status)
+    }
+
+    /// Like `probe`, but gives up after `timeout` instead of going through every retransmission
+    pub async fn probe_within(&self, timeout: Duration) -> Result<Status, Error> {
+        match tokio::time::timeout(timeout, self.probe()).await {
+            Ok(result) => result,
+            Err(_) => Err(match self.backend() {
+                Backend::Natpmp => Error::NatPmp(natpmp::Error::Timeout),
+                Backend::Pcp | Backend::Auto => Error::Pcp(pcp::Error::Timeout),
+            }),
+        }
+    }
+
+    /// Caches the gateway's public address and returns the previous one
+    fn learned_address(&self, address: IpAddr) -> Option<IpAddr> {
+        let previous = self.external_address.lock().unwrap().replace(address);
+        METRICS.set_external_address(&self.name, previous, address);
+        if previous != Some(address) {
+            self.events.publish(Change::ExternalAddressChanged {
+                gateway: self.name.clone(),
+                external_address: address,
+                previous_external_address: previous,
+            });
+        }
+        previous
+    }
+
+    /// Records the epoch of a response and signals a reset if it shows one
+    fn observe_epoch(&self, epoch: u32) {
+        let now = Instant::now();
+        let previous = self.epoch.lock().unwrap().replace((epoch, now));
+        METRICS
+            .gateway_epoch
+            .with_label_values(&[&self.name])
+            .set(epoch.into());
+        if let Some((previous_epoch, previous_at)) =
+            previous.filter(|_| is_reset(previous, epoch, now))
+        {
+            warn!(
+                "Gateway {} reset: epoch went from {}s to {}s in {}s",
+                self,
+                previous_epoch,
+                epoch,
+                now.duration_since(previous_at).as_secs()
+            );
+            self.events.publish(Change::GatewayReset {
+                gateway: self.name.clone(),
+            });
+            self.reset.notify_one();
+        }
+    }
+
+    async fn probe_any(&self) -> Result<Status, Error> {
+        match self.backend() {
+            Backend::Natpmp => self.probe_natpmp().await,
+            Backend::Pcp => self.probe_pcp().await,
+            Backend::Auto => {
+                let result = self.probe_pcp().await;
+                if self.fall_back(&result) {
+                    return self.probe_natpmp().await;
+                }
+                result
+            }
+        }
+    }
+
+    /// Records what `auto` learned from a PCP attempt and tells whether to retry with NAT-PMP
+    fn fall_back<T>(&self, result: &Result<T, Error>) -> bool {
+        let detected = match result {
+            Ok(_) => Backend::Pcp,
+            Err(Error::Pcp(pcp::Error::Gateway(pcp::ResultCode::UnsupportedVersion))) => {
+                Backend::Natpmp
+            }
+            Err(_) => return false,
+        };
+        info!("Gateway {} speaks {}", self, detected);
+        *self.detected.lock().unwrap() = Some(detected);
+        detected == Backend::Natpmp
+    }
+
+    async fn map_natpmp(&self, request: &Request) -> Result<Mapping, Error> {
+        if request.peer.is_some() {
+            return Err(Error::Unsupported("peer mappings require PCP"));
+        }
+        if request.internal_address.is_some() {
+            return Err(Error::Unsupported("mapping for another host requires PCP"));
+        }
+        let client = natpmp::Client::new(self.natpmp_address()?, self.retransmissions("natpmp"))
+            .await
+            .map_err(natpmp::Error::from)?;
+
+        // RFC 6886 section 3.4: deletes must not suggest an external port
+        let external_port = match request.lifetime {
+            0 => 0,
+            _ => request.external_port,
+        };
+        let response = client
+            .map(
+                request.protocol,
+                request.internal_port,
+                external_port,
+                request.lifetime,
+            )
+            .await?;
+        Ok(Mapping {
+            external_port: response.external_port,
+            lifetime: response.lifetime,
+            epoch: response.epoch,
+            external_address: None,
+        })
+    }
+
+    async fn map_pcp(&self, request: &Request) -> Result<Mapping, Error> {
+        let client = pcp::Client::new(self.address(), self.retransmissions("pcp"))
+            .await
+            .map_err(pcp::Error::from)?;
+
+        let response = client
+            .map(&pcp::MapRequest {
+                nonce: &self.nonce,
+                protocol: request.protocol,
+                internal_port: request.internal_port,
+                external_port: request.external_port,
+                lifetime: request.lifetime,
+                peer: request.peer,
+                third_party: request.internal_address,
+            })
+            .await?;
+        Ok(Mapping {
+            external_port: response.external_port,
+            lifetime: response.lifetime,
+            epoch: response.epoch,
+            external_address: Some(response.external_address),
+        })
+    }
+
+    async fn probe_natpmp(&self) -> Result<Status, Error> {
+        let client = natpmp::Client::new(self.natpmp_address()?, self.retransmissions("natpmp"))
+            .await
+            .map_err(natpmp::Error::from)?;
+        let response = client.public_address().await?;
+        Ok(Status {
+            backend: Backend::Natpmp,
+            epoch: response.epoch,
+            external_address: Some(IpAddr::V4(response.address)),
+        })
+    }
+
+    async fn probe_pcp(&self) -> Result<Status, Error> {
+        let client = pcp::Client::new(self.address(), self.retransmissions("pcp"))
+            .await
+            .map_err(pcp::Error::from)?;
+        let epoch = client.announce().await?;
+        Ok(Status {
+            backend: Backend::Pcp,
+            epoch,
+            external_address: None,
+        })
+    }
+
+    fn natpmp_address(&self) -> Result<std::net::Ipv4Addr, Error> {
+        match self.address() {
+            IpAddr::V4(ipv4) => Ok(ipv4),
+            IpAddr::V6(_) => Err(Error::Unsupported("NAT-PMP does not support IPv6 gateways")),
+        }
+    }
+
+    fn retransmissions(&self, protocol: &str) -> prometheus::IntCounter {
+        METRICS
+            .retransmissions
+            .with_label_values(&[&self.name, protocol])
+    }
+
+    /// Records how long a round-trip to the gateway took
+    async fn timed<T>(
+        &self,
+        operation: &str,
+        request: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        let started = Instant::now();
+        let result = request.await;
+        let outcome = if result.is_ok() { "ok" } else { "error" };
+        METRICS
+            .gateway_duration
+            .with_label_values(&[&self.name, operation, outcome])
+            .observe(started.elapsed().as_secs_f64());
+        result
+    }
+}
+
+impl fmt::Display for Gateway {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.name, self.address())
+    }
+}
+
+/// RFC 6886 section 3.6 and RFC 6887 section 8.5: the gateway lost its mappings if
+/// its epoch went backwards, or advanced at a different pace than our clock since
+/// the previous response
+fn is_reset(previous: Option<(u32, Instant)>, epoch: u32, now: Instant) -> bool {
+    let Some((previous_epoch, previous_at)) = previous else {
+        return false;
+    };
+    let client_delta = now.duration_since(previous_at).as_secs();
+    let server_delta = u64::from(epoch.saturating_sub(previous_epoch));
+    u64::from(epoch) + 1 < u64::from(previous_epoch)
+        || client_delta + 2 < server_delta - server_delta / 16
+        || server_delta + 2 < client_delta - client_delta / 16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::tests::receive;
+    use crate::transport::SERVER_PORT;
+    use tokio::net::UdpSocket;
+    use tokio::task::JoinHandle;
+
+    /// A stand-in NAT-PMP gateway on its own loopback address, and a gateway talking to it
+    async fn stand_in(address: [u8; 4]) -> (UdpSocket, Arc<Gateway>) {
+        let address = IpAddr::from(address);
+        let socket = UdpSocket::bind(SocketAddr::new(address, SERVER_PORT))
+            .await
+            .unwrap();
+        let gateway = Gateway::new(
+            format!("test-{}", address),
+            address,
+            Backend::Natpmp,
+            None,
+            EventBus::new(),
+        );
+        (socket, Arc::new(gateway))
+    }
+
+    /// Answers a mapping request with `external_port`, returning the internal port
+    /// it was for
+    async fn grant(socket: &UdpSocket, request: &[u8], to: SocketAddr, external_port: u16) -> u16 {
+        let mut response = vec![0, 128 + request[1], 0, 0, 0, 0, 0, 60];
+        response.extend_from_slice(&request[4..6]);
+        response.extend_from_slice(&external_port.to_be_bytes());
+        response.extend_from_slice(&request[8..12]);
+        socket.send_to(&response, to).await.unwrap();
+        u16::from_be_bytes([request[4], request[5]])
+    }
+
+    /// Spawns a caller mapping each internal port, in order
+    fn map_all(
+        gateway: &Arc<Gateway>,
+        internal_ports: &[u16],
+    ) -> Vec<JoinHandle<Result<Mapping, Error>>> {
+        internal_ports
+            .iter()
+            .map(|&internal_port| {
+                let gateway = gateway.clone();
+                tokio::spawn(async move {
+                    gateway
+                        .map(&Request {
+                            protocol: Protocol::Tcp,
+                            internal_port,
+                            internal_address: None,
+                            peer: None,
+                            external_port: 0,
+                            lifetime: 60,
+                        })
+                        .await
+                })
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn identical_requests_share_one_round_trip() {
+        let (socket, gateway) = stand_in([127, 0, 0, 51]).await;
+        let callers = map_all(&gateway, &[6881; 5]);
+
+        let (request, from) = receive(&socket).await;
+        assert_eq!(grant(&socket, &request, from, 40001).await, 6881);
+        for caller in callers {
+            let mapping = caller.await.unwrap().unwrap();
+            assert_eq!(mapping.external_port, 40001);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(socket.try_recv_from(&mut [0u8; 1100]).is_err());
+    }
+
+    #[tokio::test]
+    async fn different_requests_wait_their_turn() {
+        let (socket, gateway) = stand_in([127, 0, 0, 52]).await;
+        let callers = map_all(&gateway, &[6881, 6882]);
+
+        let (first, from) = receive(&socket).await;
+        // Well before NAT-PMP's first retransmission, so anything arriving now would
+        // be the second request
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(socket.try_recv_from(&mut [0u8; 1100]).is_err());
+        assert_eq!(grant(&socket, &first, from, 40001).await, 6881);
+
+        let (second, from) = receive(&socket).await;
+        assert_eq!(grant(&socket, &second, from, 40002).await, 6882);
+        let mut external_ports = Vec::new();
+        for caller in callers {
+            external_ports.push(caller.await.unwrap().unwrap().external_port);
+        }
+        assert_eq!(external_ports, [40001, 40002]);
+    }
+
+    #[test]
+    fn epoch_resets() {
+        let start = Instant::now();
+        let after = |seconds| start + Duration::from_secs(seconds);
+        // (previous epoch, epoch, seconds between the responses, reset)
+        let cases = [
+            // Normal progress, with slack for rounding and delayed responses
+            (1000, 1060, 60, false),
+            (1000, 1000, 0, false),
+            (1000, 1002, 0, false),
+            (1000, 1060, 62, false),
+            // Backwards, except for one second of reordering
+            (1000, 10, 60, true),
+            (1000, 999, 0, false),
+            (1000, 0, 0, true),
+            // Within 1/16 of the elapsed time either way
+            (1000, 2690, 1600, false),
+            (1000, 2510, 1600, false),
+            // Beyond it
+            (1000, 1200, 100, true),
+            (1000, 1010, 100, true),
+            (1000, 2800, 1600, true),
+            (1000, 2400, 1600, true),
+        ];
+        for (previous_epoch, epoch, seconds, reset) in cases {
+            assert_eq!(
+                is_reset(Some((previous_epoch, start)), epoch, after(seconds)),
+                reset,
+                "epoch {} -> {} in {}s",
+                previous_epoch,
+                epoch,
+                seconds
+            );
+        }
+        // Nothing to compare the first observation with
+        assert!(!is_reset(None, 0, start));
+        assert!(!is_reset(None, u32::MAX, start));
+    }
+}
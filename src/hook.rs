@@ -0,0 +1,304 @@
+//! Commands run when a mapping is created or its external port changes, for apps
+//! that can only be told about the port from a script
+//!
+//! Templates are split on whitespace and run without a shell, and only programs in
+//! the operator's `--hook-dir` may be run, since API clients choose the template.
+
+use crate::events::Change;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+const PLACEHOLDERS: [&str; 6] = [
+    "external_port",
+    "previous_external_port",
+    "internal_port",
+    "protocol",
+    "gateway",
+    "external_address",
+];
+
+/// Environment variables of the server that hooks must not see
+const SECRETS: [&str; 4] = [
+    "NATPMP_TOKEN",
+    "NATPMP_WEBHOOK_SECRET",
+    "NATPMP_QBITTORRENT_PASSWORD",
+    "NATPMP_TRANSMISSION_PASSWORD",
+];
+
+pub struct Hooks {
+    /// Canonical path of --hook-dir
+    dir: PathBuf,
+    timeout: Duration,
+    /// Latest run queued for each mapping, which the next one waits for
+    runs: Mutex<HashMap<String, JoinHandle<()>>>,
+}
+
+impl Hooks {
+    pub fn new(dir: &Path, timeout: Duration) -> std::io::Result<Self> {
+        Ok(Hooks {
+            dir: dir.canonicalize()?,
+            timeout,
+            runs: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Splits a template into the program to run and its arguments, and checks that
+    /// the program lies in the hook directory and the placeholders are known
+    pub fn parse(&self, template: &str) -> Result<(PathBuf, Vec<String>), String> {
+        let mut words = template.split_whitespace();
+        let program = words.next().ok_or("on_change is empty")?;
+        let program = self
+            .dir
+            .join(program)
+            .canonicalize()
+            .map_err(|e| format!("on_change program '{}': {}", program, e))?;
+        if !program.starts_with(&self.dir) {
+            return Err(format!(
+                "on_change program must be in {}",
+                self.dir.display()
+            ));
+        }
+        let args: Vec<String> = words.map(str::to_string).collect();
+        for arg in &args {
+            let mut rest = arg.as_str();
+            while let Some(start) = rest.find('{') {
+                let Some(end) = rest[start..].find('}') else {
+                    break;
+                };
+                let name = &rest[start + 1..start + end];
+                if !PLACEHOLDERS.contains(&name) {
+                    return Err(format!("Unknown placeholder {{{}}} in on_change", name));
+                }
+                rest = &rest[start + end + 1..];
+            }
+        }
+        Ok((program, args))
+    }
+
+    /// Runs the hook in the background once the earlier runs for the same mapping
+    /// finished, so the app ends up with the port of the latest change
+    pub fn queue(
+        self: &Arc<Self>,
+        mapping: String,
+        template: String,
+        change: Change,
+        external_address: Option<IpAddr>,
+    ) {
+        let hooks = self.clone();
+        let mut runs = self.runs.lock().unwrap();
+        runs.retain(|_, run| !run.is_finished());
+        let previous = runs.remove(&mapping);
+        let run = tokio::spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+            hooks.run(&template, &change, external_address).await;
+        });
+        runs.insert(mapping, run);
+    }
+
+    /// Runs the mapping's hook for a change, logging its output. Runs that exceed the
+    /// timeout are killed.
+    async fn run(&self, template: &str, change: &Change, external_address: Option<IpAddr>) {
+        let (mapping, previous_external_port) = match change {
+            Change::MappingCreated(mapping) => (mapping, None),
+            Change::ExternalPortChanged {
+                mapping,
+                previous_external_port,
+            } => (mapping, Some(*previous_external_port)),
+            _ => return,
+        };
+        let (program, args) = match self.parse(template) {
+            Ok(command) => command,
+            // The program may have been removed since the mapping was made
+            Err(e) => {
+                warn!("Not running hook: {}", e);
+                return;
+            }
+        };
+        let values = [
+            mapping.external_port.to_string(),
+            previous_external_port.map_or_else(String::new, |port| port.to_string()),
+            mapping.internal_port.to_string(),
+            mapping.protocol.clone(),
+            mapping.gateway.clone(),
+            external_address.map_or_else(String::new, |address| address.to_string()),
+        ];
+
+        let mut command = Command::new(&program);
+        for arg in &args {
+            let arg = PLACEHOLDERS
+                .iter()
+                .zip(&values)
+                .fold(arg.clone(), |arg, (name, value)| {
+                    arg.replace(&format!("{{{}}}", name), value)
+                });
+            command.arg(arg);
+        }
+        for (name, value) in PLACEHOLDERS.iter().zip(&values) {
+            command.env(format!("MAPPING_{}", name.to_uppercase()), value);
+        }
+        for secret in SECRETS {
+            command.env_remove(secret);
+        }
+        command
+            .env("MAPPING_EVENT", change.kind())
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+
+        let name = program.display();
+        let output = match tokio::time::timeout(self.timeout, command.output()).await {
+            Ok(Ok(output)) => output,
+            Ok(Err(e)) => {
+                warn!("Failed to run hook {}: {}", name, e);
+                return;
+            }
+            Err(_) => {
+                warn!(
+                    "Hook {} did not finish within {}s, killed it",
+                    name,
+                    self.timeout.as_secs()
+                );
+                return;
+            }
+        };
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            info!("Hook {}: {}", name, line);
+        }
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            warn!("Hook {}: {}", name, line);
+        }
+        if output.status.success() {
+            info!(
+                "Hook {} ran for {}/{} -> {}",
+                name, mapping.internal_port, mapping.protocol, mapping.external_port
+            );
+        } else {
+            warn!("Hook {} failed: {}", name, output.status);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::MappingDetails;
+    use chrono::Utc;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A fresh hook directory under the system's temporary directory
+    fn hook_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("natpmp-hooks-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn script(dir: &Path, name: &str, body: &str) {
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    fn port_changed(external_port: u16, previous_external_port: u16) -> Change {
+        Change::ExternalPortChanged {
+            mapping: MappingDetails {
+                gateway: "default".to_string(),
+                via: None,
+                protocol: "tcp".to_string(),
+                internal_port: 6881,
+                internal_address: None,
+                peer: None,
+                external_port,
+                lifetime: 60,
+                expires_at: Utc::now(),
+                managed: true,
+            },
+            previous_external_port,
+        }
+    }
+
+    #[test]
+    fn parses_programs_in_the_directory() {
+        let dir = hook_dir("parse");
+        let outside = hook_dir("parse-outside");
+        script(&dir, "notify.sh", "true");
+        script(&outside, "escape.sh", "true");
+        std::os::unix::fs::symlink(outside.join("escape.sh"), dir.join("link.sh")).unwrap();
+        let hooks = Hooks::new(&dir, Duration::from_secs(5)).unwrap();
+
+        let (program, args) = hooks
+            .parse("notify.sh --port={external_port} {protocol} plain")
+            .unwrap();
+        assert_eq!(program, dir.canonicalize().unwrap().join("notify.sh"));
+        assert_eq!(args, ["--port={external_port}", "{protocol}", "plain"]);
+
+        let escape = format!(
+            "../{}/escape.sh",
+            outside.file_name().unwrap().to_string_lossy()
+        );
+        let absolute = outside.join("escape.sh").display().to_string();
+        for template in [
+            "",
+            "  ",
+            "missing.sh",
+            &escape,
+            &absolute,
+            "link.sh",
+            "notify.sh {port}",
+        ] {
+            assert!(
+                hooks.parse(template).is_err(),
+                "{:?} was accepted",
+                template
+            );
+        }
+        assert_eq!(
+            hooks
+                .parse("notify.sh {external_port}{secret}")
+                .unwrap_err(),
+            "Unknown placeholder {secret} in on_change"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[tokio::test]
+    async fn runs_in_order_per_mapping() {
+        let dir = hook_dir("order");
+        // The first run is the slow one, so it would finish last without the queue
+        script(
+            &dir,
+            "record.sh",
+            r#"[ "$1" = 40001 ] && sleep 0.3; echo "$1" >> "$(dirname "$0")/ports""#,
+        );
+        let hooks = Arc::new(Hooks::new(&dir, Duration::from_secs(5)).unwrap());
+        for (port, previous) in [(40001, 40000), (40002, 40001)] {
+            hooks.queue(
+                "6881/tcp via default".to_string(),
+                "record.sh {external_port}".to_string(),
+                port_changed(port, previous),
+                None,
+            );
+        }
+        let last = hooks
+            .runs
+            .lock()
+            .unwrap()
+            .remove("6881/tcp via default")
+            .unwrap();
+        last.await.unwrap();
+
+        let ports = std::fs::read_to_string(dir.join("ports")).unwrap();
+        assert_eq!(ports, "40001\n40002\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
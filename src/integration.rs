@@ -0,0 +1,155 @@
+//! Keeps an application's listening port in line with the external port of one
+//! mapping, for clients (BitTorrent, mostly) that announce the port to peers
+
+use crate::events::{Change, Event};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{info, warn};
+
+/// Wait before trying again to tell an application that could not be reached
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// An application whose port can be set remotely
+pub trait PortTarget {
+    fn name(&self) -> &'static str;
+
+    async fn set_port(&mut self, port: u16) -> Result<(), String>;
+}
+
+/// The mapping an application follows, given as `port/protocol`, optionally with
+/// `@gateway` (or failover group)
+#[derive(Clone, Debug)]
+pub struct MappingSpec {
+    pub internal_port: u16,
+    pub protocol: String,
+    pub gateway: Option<String>,
+}
+
+impl FromStr for MappingSpec {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (mapping, gateway) = match value.split_once('@') {
+            Some((mapping, gateway)) => (mapping, Some(gateway.to_string())),
+            None => (value, None),
+        };
+        let Some((port, protocol)) = mapping.split_once('/') else {
+            return Err("expected port/protocol, e.g. 6881/tcp".to_string());
+        };
+        let internal_port = port
+            .parse()
+            .map_err(|_| format!("invalid port '{}'", port))?;
+        let protocol = protocol.to_lowercase();
+        if protocol != "tcp" && protocol != "udp" {
+            return Err("protocol must be tcp or udp".to_string());
+        }
+        Ok(MappingSpec {
+            internal_port,
+            protocol,
+            gateway,
+        })
+    }
+}
+
+impl fmt::Display for MappingSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.internal_port, self.protocol)?;
+        if let Some(gateway) = &self.gateway {
+            write!(f, "@{}", gateway)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sets the application's port whenever an event shows the mapping with a port the
+/// application was not given yet, which includes the first renewal after a restart.
+/// `gateway` is the name the mapping is kept under.
+pub async fn follow(
+    mut target: impl PortTarget,
+    mapping: MappingSpec,
+    gateway: String,
+    events: impl Stream<Item = Result<Arc<Event>, u64>>,
+) {
+    let mut events = std::pin::pin!(events);
+    let mut applied = None;
+    // Port the application still has to be given, and when to try again
+    let mut pending: Option<(u16, Instant)> = None;
+    loop {
+        let retry_at = pending.map(|(_, at)| at);
+        let port = tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else {
+                    return;
+                };
+                let Ok(event) = event else {
+                    // Missed events may include a port change, so apply the next one regardless
+                    applied = None;
+                    continue;
+                };
+                let Some(port) = port_of(&event, &mapping, &gateway) else {
+                    continue;
+                };
+                if applied == Some(port) || pending.is_some_and(|(pending, _)| pending == port) {
+                    continue;
+                }
+                port
+            }
+            _ = retry(retry_at) => pending.expect("Retry without a pending port").0,
+        };
+        match apply(&mut target, port).await {
+            Ok(()) => {
+                applied = Some(port);
+                pending = None;
+            }
+            Err(()) => pending = Some((port, Instant::now() + RETRY_INTERVAL)),
+        }
+    }
+}
+
+/// External port of the followed mapping, if the event shows it in place
+fn port_of(event: &Event, mapping: &MappingSpec, gateway: &str) -> Option<u16> {
+    let details = match &event.change {
+        Change::MappingCreated(details)
+        | Change::MappingRenewed(details)
+        | Change::ExternalPortChanged {
+            mapping: details, ..
+        } => details,
+        _ => return None,
+    };
+    // A PEER mapping's port only reaches one remote host, so apps cannot listen on it
+    (details.peer.is_none()
+        && details.internal_port == mapping.internal_port
+        && details.protocol == mapping.protocol
+        && details.gateway == gateway)
+        .then_some(details.external_port)
+}
+
+async fn retry(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn apply(target: &mut impl PortTarget, port: u16) -> Result<(), ()> {
+    match target.set_port(port).await {
+        Ok(()) => {
+            info!("Set {} listening port to {}", target.name(), port);
+            Ok(())
+        }
+        Err(e) => {
+            warn!(
+                "Failed to set {} listening port to {}, retrying in {}s: {}",
+                target.name(),
+                port,
+                RETRY_INTERVAL.as_secs(),
+                e
+            );
+            Err(())
+        }
+    }
+}
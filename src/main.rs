@@ -1,25 +1,98 @@
+mod announce;
+mod error;
+mod events;
+mod failover;
+mod gateway;
+mod hook;
+mod integration;
+mod metrics;
+mod natpmp;
+mod pcp;
+mod port_file;
+mod qbittorrent;
+mod route;
+mod state_file;
+mod transmission;
+mod transport;
+mod webhook;
+
 use axum::{
-    extract::State,
+    extract::{ConnectInfo, Path, Query, State},
     http::{HeaderMap, StatusCode},
+    response::sse::{self, KeepAlive, Sse},
     response::Json,
     routing::{get, post},
     Router,
 };
+use chrono::{DateTime, Utc};
 use clap::Parser;
-use natpmp::{Natpmp, Protocol, Response};
+use error::ApiError;
+use events::{Change, Event, EventBus, Filter, MappingDetails};
+use failover::{FailoverGroup, GroupSpec};
+use gateway::{Backend, Gateway, GatewayAddress, GatewaySpec, Mapping, Protocol, Request};
+use hook::Hooks;
+use integration::MappingSpec;
+use metrics::METRICS;
+use port_file::PortFiles;
+use qbittorrent::Qbittorrent;
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
-use std::net::IpAddr;
+use sha2::{Digest, Sha256};
+use state_file::{SavedLease, SavedMapping, Snapshot, StateFile};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::net::TcpListener;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_stream::{Stream, StreamExt};
 use tower_http::trace::TraceLayer;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
+use transmission::Transmission;
+use webhook::Webhook;
 
 #[derive(Parser)]
 #[command(name = "natpmp-server")]
 #[command(about = "NAT-PMP HTTP Server for Kubernetes")]
 struct Args {
-    /// VPN gateway IP address
-    #[arg(long, required = true, env = "NATPMP_GATEWAY")]
-    gateway: IpAddr,
+    /// VPN gateway IP address, or `name=address` for each of several gateways. `auto`
+    /// takes the gateway from the routing table (`auto%wg0` through that interface).
+    #[arg(long, required = true, value_delimiter = ',', env = "NATPMP_GATEWAY")]
+    gateway: Vec<GatewaySpec>,
+
+    /// Interface whose routes `--gateway auto` follows, instead of the default route
+    #[arg(long, env = "NATPMP_GATEWAY_INTERFACE")]
+    gateway_interface: Option<String>,
+
+    /// Seconds between routing table checks for gateways set to `auto`
+    #[arg(long, default_value = "10", env = "NATPMP_ROUTE_INTERVAL")]
+    route_interval: u64,
+
+    /// Gateway or failover group for requests that do not name one (default: the
+    /// first --gateway)
+    #[arg(long, env = "NATPMP_DEFAULT_GATEWAY")]
+    default_gateway: Option<String>,
+
+    /// Failover group as `name=gateway,gateway,...` in order of preference (repeat for
+    /// several groups)
+    #[arg(long, env = "NATPMP_FAILOVER_GROUP")]
+    failover_group: Vec<GroupSpec>,
+
+    /// Seconds between health checks of each failover group's active gateway
+    #[arg(long, default_value = "15", env = "NATPMP_FAILOVER_INTERVAL")]
+    failover_interval: u64,
+
+    /// Consecutive failed health checks before a failover group leaves its active
+    /// gateway, so a single lost probe does not move every mapping
+    #[arg(long, default_value = "3", env = "NATPMP_FAILOVER_CHECKS")]
+    failover_checks: u32,
+
+    /// Port mapping protocol spoken to the gateway
+    #[arg(long, value_enum, default_value = "natpmp", env = "NATPMP_PROTOCOL")]
+    protocol: Backend,
 
     /// Server bind address
     #[arg(long, default_value = "0.0.0.0", env = "NATPMP_BIND_ADDRESS")]
@@ -33,6 +106,89 @@ struct Args {
     #[arg(long, default_value = "300", env = "NATPMP_MAX_DURATION")]
     max_duration: i32,
 
+    /// Seconds a gateway response keeps /ready from probing the gateway again
+    #[arg(long, default_value = "30", env = "NATPMP_READY_MAX_AGE")]
+    ready_max_age: u64,
+
+    /// Seconds /ready and failover checks wait for the gateway to answer a probe
+    #[arg(long, default_value = "3", env = "NATPMP_READY_TIMEOUT")]
+    ready_timeout: u64,
+
+    /// Delete every mapping on the gateway when shutting down
+    #[arg(long, env = "NATPMP_RELEASE_ON_SHUTDOWN")]
+    release_on_shutdown: bool,
+
+    /// Seconds to wait for the gateway to confirm deletes on shutdown
+    #[arg(long, default_value = "10", env = "NATPMP_SHUTDOWN_TIMEOUT")]
+    shutdown_timeout: u64,
+
+    /// URL to post changes of managed mappings' external ports and of public
+    /// addresses to (repeat for several)
+    #[arg(long, env = "NATPMP_WEBHOOK")]
+    webhook: Vec<Url>,
+
+    /// Key to sign webhook payloads with (HMAC-SHA256 in X-Natpmp-Signature)
+    #[arg(long, env = "NATPMP_WEBHOOK_SECRET", hide_env_values = true)]
+    webhook_secret: Option<String>,
+
+    /// Times to retry a failed webhook delivery, with exponential backoff
+    #[arg(long, default_value = "5", env = "NATPMP_WEBHOOK_RETRIES")]
+    webhook_retries: u32,
+
+    /// Directory of programs that mappings may run as on_change hooks (hooks are
+    /// refused without it)
+    #[arg(long, env = "NATPMP_HOOK_DIR")]
+    hook_dir: Option<PathBuf>,
+
+    /// Seconds an on_change hook may run before it is killed
+    #[arg(long, default_value = "30", env = "NATPMP_HOOK_TIMEOUT")]
+    hook_timeout: u64,
+
+    /// qBittorrent WebUI to set the listening port of to the external port of
+    /// --qbittorrent-mapping
+    #[arg(long, env = "NATPMP_QBITTORRENT_URL")]
+    qbittorrent_url: Option<Url>,
+
+    /// Mapping whose external port qBittorrent listens on, as `port/protocol`
+    /// (optionally `@gateway`)
+    #[arg(long, env = "NATPMP_QBITTORRENT_MAPPING")]
+    qbittorrent_mapping: Option<MappingSpec>,
+
+    /// qBittorrent WebUI username (omit when the WebUI skips login for this host)
+    #[arg(long, env = "NATPMP_QBITTORRENT_USERNAME")]
+    qbittorrent_username: Option<String>,
+
+    /// qBittorrent WebUI password
+    #[arg(long, env = "NATPMP_QBITTORRENT_PASSWORD", hide_env_values = true)]
+    qbittorrent_password: Option<String>,
+
+    /// Transmission RPC endpoint (e.g. http://localhost:9091/transmission/rpc) to set
+    /// the peer port of to the external port of --transmission-mapping
+    #[arg(long, env = "NATPMP_TRANSMISSION_URL")]
+    transmission_url: Option<Url>,
+
+    /// Mapping whose external port Transmission listens on, as `port/protocol`
+    /// (optionally `@gateway`)
+    #[arg(long, env = "NATPMP_TRANSMISSION_MAPPING")]
+    transmission_mapping: Option<MappingSpec>,
+
+    /// Transmission RPC username
+    #[arg(long, env = "NATPMP_TRANSMISSION_USERNAME")]
+    transmission_username: Option<String>,
+
+    /// Transmission RPC password
+    #[arg(long, env = "NATPMP_TRANSMISSION_PASSWORD", hide_env_values = true)]
+    transmission_password: Option<String>,
+
+    /// Directory that mappings may write their port_file into (port files are
+    /// refused without it)
+    #[arg(long, env = "NATPMP_PORT_FILE_DIR")]
+    port_file_dir: Option<PathBuf>,
+
+    /// File to keep mappings in, so they are re-created after a restart
+    #[arg(long, env = "NATPMP_STATE_FILE")]
+    state_file: Option<PathBuf>,
+
     /// Log level
     #[arg(long, default_value = "info", env = "NATPMP_LOG_LEVEL")]
     log_level: String,
@@ -40,9 +196,138 @@ struct Args {
 
 #[derive(Clone)]
 struct AppState {
-    gateway: IpAddr,
+    /// Every configured gateway, in command line order
+    gateways: Arc<Vec<Arc<Gateway>>>,
+    groups: Arc<Vec<Arc<FailoverGroup>>>,
+    /// Gateway or failover group for requests that do not name one
+    default_gateway: String,
     max_duration: Option<u32>,
     token: Option<String>,
+    mappings: Arc<Mutex<HashMap<MappingKey, MappingEntry>>>,
+    state_file: Option<Arc<StateFile>>,
+    events: EventBus,
+    hooks: Option<Arc<Hooks>>,
+    port_files: Option<Arc<PortFiles>>,
+    /// How long a gateway response counts as proof that it is reachable
+    ready_max_age: Duration,
+    ready_timeout: Duration,
+    failover_interval: Duration,
+    failover_checks: u32,
+    /// Set while the mappings of the previous run are being re-created
+    restoring: Arc<AtomicBool>,
+}
+
+impl AppState {
+    /// The gateway a request named (or the default one), along with the name its
+    /// mappings are kept under: the gateway's own, or that of the failover group it
+    /// is the active member of
+    fn gateway(&self, name: Option<&str>) -> Result<(String, Arc<Gateway>), ApiError> {
+        let name = name.unwrap_or(&self.default_gateway);
+        if let Some(group) = self.groups.iter().find(|group| group.name == name) {
+            return Ok((group.name.clone(), group.active()));
+        }
+        match self.gateways.iter().find(|gateway| gateway.name == name) {
+            Some(gateway) => Ok((gateway.name.clone(), gateway.clone())),
+            None => Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "unknown_gateway",
+                format!("Unknown gateway '{}'", name),
+            )),
+        }
+    }
+
+    /// Sets of gateways of which at least one has to answer for the server to be
+    /// ready: each failover group's members, and every other gateway on its own
+    fn readiness_sets(&self, name: Option<&str>) -> Result<Vec<Vec<Arc<Gateway>>>, ApiError> {
+        if let Some(name) = name {
+            if let Some(group) = self.groups.iter().find(|group| group.name == name) {
+                return Ok(vec![group.members.clone()]);
+            }
+            return Ok(vec![vec![self.gateway(Some(name))?.1]]);
+        }
+        let mut sets: Vec<Vec<Arc<Gateway>>> = self
+            .groups
+            .iter()
+            .map(|group| group.members.clone())
+            .collect();
+        for gateway in self.gateways.iter() {
+            if !sets
+                .iter()
+                .flatten()
+                .any(|member| Arc::ptr_eq(member, gateway))
+            {
+                sets.push(vec![gateway.clone()]);
+            }
+        }
+        Ok(sets)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct MappingKey {
+    /// Name of the gateway the mapping was made on
+    gateway: String,
+    protocol: String,
+    internal_port: u16,
+    /// Host the mapping is for when it is not this server
+    internal_address: Option<IpAddr>,
+    /// Remote peer of a PCP PEER mapping, which may share its internal port with
+    /// an inbound mapping
+    peer: Option<SocketAddr>,
+}
+
+impl std::fmt::Display for MappingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(address) = self.internal_address {
+            write!(f, "{} ", address)?;
+        }
+        write!(
+            f,
+            "{}/{} via {}",
+            self.internal_port, self.protocol, self.gateway
+        )?;
+        if let Some(peer) = self.peer {
+            write!(f, " to {}", peer)?;
+        }
+        Ok(())
+    }
+}
+
+/// A mapping created through `forward`
+struct MappingEntry {
+    gateway: Arc<Gateway>,
+    /// What was last asked of the gateway, replayed on renewal
+    request: Request,
+    external_port: u16,
+    lifetime: u32,
+    expires_at: DateTime<Utc>,
+    client_address: SocketAddr,
+    token_id: Option<String>,
+    /// Drop the mapping rather than accept another external port
+    require_exact: bool,
+    lease: Option<Lease>,
+    /// Command template run when the mapping is created or its external port changes
+    on_change: Option<String>,
+    /// File in --port-file-dir kept holding the external port
+    port_file: Option<String>,
+    port_file_format: port_file::Format,
+}
+
+/// Keeps a mapping renewed on behalf of a client
+struct Lease {
+    /// How long the client may stay silent before the lease lapses (None = until released)
+    keepalive: Option<Duration>,
+    last_seen: Instant,
+    task: JoinHandle<()>,
+}
+
+impl Lease {
+    fn lapsed(&self) -> bool {
+        match self.keepalive {
+            None => false,
+            Some(keepalive) => self.last_seen.elapsed() > keepalive,
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -50,6 +335,39 @@ struct ForwardRequest {
     internal_port: u16,
     protocol: String,
     duration: u32,
+    /// Seconds the server keeps renewing the mapping without hearing from the
+    /// client (0 = until released). When omitted the mapping is not managed.
+    #[serde(default)]
+    keepalive: Option<u32>,
+    /// Remote peer (address:port) to request a PCP PEER mapping for instead of an
+    /// inbound mapping
+    #[serde(default)]
+    peer: Option<SocketAddr>,
+    /// Host to map the port for instead of this server, e.g. an IPv6 host that
+    /// needs a firewall pinhole (PCP only)
+    #[serde(default)]
+    internal_address: Option<IpAddr>,
+    /// Public port to suggest to the gateway instead of letting it choose
+    #[serde(default)]
+    external_port: Option<u16>,
+    /// Fail with a conflict (and delete the mapping) if the gateway assigns a
+    /// different public port than `external_port`
+    #[serde(default)]
+    require_exact: bool,
+    /// Name of the gateway to map the port on (default: the default gateway)
+    #[serde(default)]
+    gateway: Option<String>,
+    /// Command from --hook-dir to run when the mapping is created or its external
+    /// port changes, e.g. `set-port.sh {external_port} {protocol}`
+    #[serde(default)]
+    on_change: Option<String>,
+    /// File in --port-file-dir to keep the external port in, removed when the
+    /// mapping is released or expires
+    #[serde(default)]
+    port_file: Option<String>,
+    /// `port` for just the number, `json` to add the address and expiry
+    #[serde(default)]
+    port_file_format: port_file::Format,
 }
 
 #[derive(Serialize)]
@@ -58,33 +376,347 @@ struct ForwardResponse {
     external_port: u16,
     protocol: String,
     duration: u32,
+    /// Public address of the gateway, when known
+    external_address: Option<IpAddr>,
+    gateway: String,
+    /// Member of the failover group named by `gateway` that holds the mapping
+    #[serde(skip_serializing_if = "Option::is_none")]
+    via: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ReleaseRequest {
+    internal_port: u16,
+    protocol: String,
+    #[serde(default)]
+    internal_address: Option<IpAddr>,
+    #[serde(default)]
+    peer: Option<SocketAddr>,
+    #[serde(default)]
+    gateway: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MappingQuery {
+    internal_address: Option<IpAddr>,
+    peer: Option<SocketAddr>,
+    gateway: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GatewayQuery {
+    gateway: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EventQuery {
+    internal_port: Option<u16>,
+    protocol: Option<String>,
+    gateway: Option<String>,
 }
 
 #[derive(Serialize)]
-struct HealthResponse {
+struct ReleaseResponse {
+    internal_port: u16,
+    protocol: String,
+    /// Whether the gateway confirmed that the mapping was deleted
+    removed: bool,
+    gateway: String,
+}
+
+#[derive(Serialize)]
+struct MappingInfo {
+    gateway: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    via: Option<String>,
+    internal_port: u16,
+    protocol: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    internal_address: Option<IpAddr>,
+    external_port: u16,
+    lifetime: u32,
+    expires_at: String,
+    client_address: String,
+    /// Fingerprint of the bearer token the mapping was requested with
+    token_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer: Option<SocketAddr>,
+    managed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    on_change: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port_file: Option<String>,
+}
+
+impl MappingInfo {
+    fn new(key: &MappingKey, entry: &MappingEntry) -> Self {
+        MappingInfo {
+            gateway: key.gateway.clone(),
+            via: (entry.gateway.name != key.gateway).then(|| entry.gateway.name.clone()),
+            internal_port: key.internal_port,
+            protocol: key.protocol.clone(),
+            internal_address: key.internal_address,
+            external_port: entry.external_port,
+            lifetime: entry.lifetime,
+            expires_at: entry.expires_at.to_rfc3339(),
+            client_address: entry.client_address.to_string(),
+            token_id: entry.token_id.clone(),
+            peer: key.peer,
+            managed: entry.lease.is_some(),
+            on_change: entry.on_change.clone(),
+            port_file: entry.port_file.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ExternalAddressResponse {
+    gateway: String,
+    external_address: IpAddr,
+    /// Seconds since the gateway's port mapping table was initialized
+    epoch: u32,
+}
+
+#[derive(Serialize)]
+struct ReadyResponse {
+    /// `ready` when every gateway checked is reachable, or for failover groups at
+    /// least one of their members, `restoring` while saved mappings are re-created
     status: String,
-    timestamp: String,
+    gateways: Vec<GatewayReadiness>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failover_groups: Vec<GroupReadiness>,
+}
+
+#[derive(Serialize)]
+struct GroupReadiness {
+    name: String,
+    /// Gateway that currently holds the group's mappings
+    active: String,
 }
 
 #[derive(Serialize)]
-struct ErrorResponse {
-    error: String,
+struct GatewayReadiness {
+    name: String,
+    address: IpAddr,
+    /// `ready` or `unreachable`
+    status: String,
+    backend: String,
+    /// Seconds since the gateway last answered
+    last_response: Option<u64>,
+    external_address: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    status: String,
+    timestamp: String,
 }
 
 fn check_authorization(headers: &HeaderMap, expected_token: &Option<String>) -> bool {
-    match expected_token {
+    let authorized = match expected_token {
         None => true, // No token required
-        Some(token) => {
-            if let Some(auth_header) = headers.get("authorization") {
-                if let Ok(auth_str) = auth_header.to_str() {
-                    return auth_str == format!("Bearer {}", token);
-                }
+        Some(token) => match headers.get("authorization").map(|header| header.to_str()) {
+            Some(Ok(auth_str)) => auth_str == format!("Bearer {}", token),
+            _ => false,
+        },
+    };
+    if !authorized {
+        METRICS.auth_failures.inc();
+    }
+    authorized
+}
+
+fn parse_protocol(protocol: &str) -> Result<Protocol, ApiError> {
+    match protocol.to_lowercase().as_str() {
+        "tcp" => Ok(Protocol::Tcp),
+        "udp" => Ok(Protocol::Udp),
+        _ => Err(ApiError::bad_request("protocol must be tcp or udp")),
+    }
+}
+
+/// Identifies the token a request was made with without exposing it
+fn token_id(expected_token: &Option<String>) -> Option<String> {
+    expected_token.as_ref().map(|token| {
+        Sha256::digest(token.as_bytes())[..4]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    })
+}
+
+/// Stops renewing a managed mapping, if there is one
+fn release_lease(state: &AppState, key: &MappingKey) {
+    let mut mappings = state.mappings.lock().unwrap();
+    if let Some(lease) = mappings.get_mut(key).and_then(|entry| entry.lease.take()) {
+        lease.task.abort();
+        info!("Released lease for {}", key);
+        persist(state);
+    }
+}
+
+/// Schedules a write of the state file, if there is one
+fn persist(state: &AppState) {
+    if let Some(state_file) = &state.state_file {
+        state_file.mark_changed();
+    }
+}
+
+/// Drops mappings whose lifetime has run out on the gateway
+fn prune_expired(state: &AppState, mappings: &mut HashMap<MappingKey, MappingEntry>) {
+    let now = Utc::now();
+    mappings.retain(|key, entry| {
+        let alive = entry.lease.is_some() || entry.expires_at > now;
+        if !alive {
+            mapping_removed(state, key, entry, Change::MappingExpired);
+        }
+        alive
+    });
+}
+
+/// Forgets a mapping that is gone from the gateway, or about to be
+fn forget_mapping(state: &AppState, key: &MappingKey) {
+    let removed = state.mappings.lock().unwrap().remove(key);
+    if let Some(entry) = removed {
+        mapping_removed(state, key, &entry, Change::MappingDeleted);
+    }
+}
+
+/// Tells subscribers that a mapping is gone, and removes its port file
+fn mapping_removed(
+    state: &AppState,
+    key: &MappingKey,
+    entry: &MappingEntry,
+    change: fn(MappingDetails) -> Change,
+) {
+    if let Some(name) = &entry.port_file {
+        remove_port_file(state, name);
+    }
+    state.events.publish(change(mapping_details(key, entry)));
+}
+
+fn remove_port_file(state: &AppState, name: &str) {
+    if let Some(port_files) = &state.port_files {
+        if let Err(e) = port_files.remove(name) {
+            warn!("Failed to remove port file {}: {}", name, e);
+        }
+    }
+}
+
+fn write_port_file(state: &AppState, key: &MappingKey, entry: &MappingEntry) {
+    if let (Some(port_files), Some(name)) = (&state.port_files, &entry.port_file) {
+        let details = mapping_details(key, entry);
+        let external_address = entry.gateway.external_address();
+        if let Err(e) = port_files.write(name, entry.port_file_format, &details, external_address) {
+            warn!("Failed to write port file {}: {}", name, e);
+        }
+    }
+}
+
+/// Puts a gateway's new public address into the JSON port files of its mappings
+async fn update_port_files(state: AppState, events: impl Stream<Item = Result<Arc<Event>, u64>>) {
+    let mut events = std::pin::pin!(events);
+    while let Some(event) = events.next().await {
+        // Missed events may include an address change, so refresh every file then
+        let gateway = match event.as_deref() {
+            Ok(Event {
+                change: Change::ExternalAddressChanged { gateway, .. },
+                ..
+            }) => Some(gateway.as_str()),
+            Ok(_) => continue,
+            Err(_) => None,
+        };
+        let mappings = state.mappings.lock().unwrap();
+        for (key, entry) in mappings.iter() {
+            if entry.port_file_format == port_file::Format::Json
+                && gateway.is_none_or(|name| entry.gateway.name == name)
+            {
+                write_port_file(&state, key, entry);
             }
-            false
         }
     }
 }
 
+/// Tells subscribers that a mapping was made (no previous port) or renewed, brings
+/// its port file up to date and runs its hook when it got a new external port
+fn mapping_updated(
+    state: &AppState,
+    key: &MappingKey,
+    entry: &MappingEntry,
+    previous_port: Option<u16>,
+) {
+    write_port_file(state, key, entry);
+    let details = mapping_details(key, entry);
+    let change = match previous_port {
+        None => Change::MappingCreated(details),
+        Some(port) if port != entry.external_port => Change::ExternalPortChanged {
+            mapping: details,
+            previous_external_port: port,
+        },
+        Some(_) => Change::MappingRenewed(details),
+    };
+    if let (Some(hooks), Some(template)) = (&state.hooks, &entry.on_change) {
+        if !matches!(change, Change::MappingRenewed(_)) {
+            hooks.queue(
+                key.to_string(),
+                template.clone(),
+                change.clone(),
+                entry.gateway.external_address(),
+            );
+        }
+    }
+    state.events.publish(change);
+}
+
+fn mapping_details(key: &MappingKey, entry: &MappingEntry) -> MappingDetails {
+    MappingDetails {
+        gateway: key.gateway.clone(),
+        via: (entry.gateway.name != key.gateway).then(|| entry.gateway.name.clone()),
+        protocol: key.protocol.clone(),
+        internal_port: key.internal_port,
+        internal_address: key.internal_address,
+        peer: key.peer,
+        external_port: entry.external_port,
+        lifetime: entry.lifetime,
+        expires_at: entry.expires_at,
+        managed: entry.lease.is_some(),
+    }
+}
+
+fn expires_at(lifetime: u32) -> DateTime<Utc> {
+    Utc::now() + chrono::Duration::seconds(lifetime.into())
+}
+
+async fn metrics(State(state): State<AppState>) -> String {
+    {
+        let mut mappings = state.mappings.lock().unwrap();
+        prune_expired(&state, &mut mappings);
+        let mut counts: HashMap<&str, (i64, i64)> = state
+            .gateways
+            .iter()
+            .map(|gateway| (gateway.name.as_str(), (0, 0)))
+            .collect();
+        for entry in mappings.values() {
+            let (total, managed) = counts.entry(entry.gateway.name.as_str()).or_default();
+            *total += 1;
+            if entry.lease.is_some() {
+                *managed += 1;
+            }
+        }
+        for (gateway, (total, managed)) in counts {
+            METRICS.mappings.with_label_values(&[gateway]).set(total);
+            METRICS
+                .managed_mappings
+                .with_label_values(&[gateway])
+                .set(managed);
+        }
+    }
+    METRICS.render()
+}
+
 async fn health() -> Json<HealthResponse> {
     Json(HealthResponse {
         status: "healthy".to_string(),
@@ -92,19 +724,147 @@ async fn health() -> Json<HealthResponse> {
     })
 }
 
+/// Readiness: whether the gateways (or the one asked for) answered recently, probing
+/// those that have been quiet for longer than the freshness window
+async fn ready(
+    State(state): State<AppState>,
+    Query(query): Query<GatewayQuery>,
+) -> Result<(StatusCode, Json<ReadyResponse>), ApiError> {
+    let sets = state.readiness_sets(query.gateway.as_deref())?;
+    let mut gateways: Vec<Arc<Gateway>> = Vec::new();
+    for gateway in sets.iter().flatten() {
+        if !gateways.iter().any(|known| Arc::ptr_eq(known, gateway)) {
+            gateways.push(gateway.clone());
+        }
+    }
+
+    // Probe concurrently so one dead tunnel does not delay the others
+    let checks: Vec<_> = gateways
+        .into_iter()
+        .map(|gateway| {
+            tokio::spawn(check_gateway(
+                gateway,
+                state.ready_max_age,
+                state.ready_timeout,
+            ))
+        })
+        .collect();
+    let mut gateways = Vec::new();
+    for check in checks {
+        gateways.push(check.await.expect("Readiness check panicked"));
+    }
+
+    let answered = |gateway: &Arc<Gateway>| {
+        gateways
+            .iter()
+            .any(|checked| checked.name == gateway.name && checked.status == "ready")
+    };
+    let ready = sets.iter().all(|set| set.iter().any(answered));
+    let failover_groups = state
+        .groups
+        .iter()
+        .filter(|group| {
+            query
+                .gateway
+                .as_ref()
+                .is_none_or(|name| group.name == *name)
+        })
+        .map(|group| GroupReadiness {
+            name: group.name.clone(),
+            active: group.active().name.clone(),
+        })
+        .collect();
+    let (status, description) = if state.restoring.load(Ordering::Relaxed) {
+        (StatusCode::SERVICE_UNAVAILABLE, "restoring")
+    } else if ready {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not_ready")
+    };
+    Ok((
+        status,
+        Json(ReadyResponse {
+            status: description.to_string(),
+            gateways,
+            failover_groups,
+        }),
+    ))
+}
+
+async fn check_gateway(
+    gateway: Arc<Gateway>,
+    max_age: Duration,
+    timeout: Duration,
+) -> GatewayReadiness {
+    let fresh = gateway
+        .last_response()
+        .is_some_and(|at| at.elapsed() <= max_age);
+    let failure = match fresh {
+        true => None,
+        false => gateway.probe_within(timeout).await.err(),
+    };
+    let last_response = gateway.last_response().map(|at| at.elapsed().as_secs());
+    if let Some(e) = &failure {
+        match last_response {
+            Some(seconds) => warn!(
+                "Gateway {} unreachable (last answered {}s ago): {}",
+                gateway, seconds, e
+            ),
+            None => warn!("Gateway {} unreachable: {}", gateway, e),
+        }
+    }
+
+    GatewayReadiness {
+        name: gateway.name.clone(),
+        address: gateway.address(),
+        status: match failure {
+            None => "ready".to_string(),
+            Some(_) => "unreachable".to_string(),
+        },
+        backend: gateway.backend().to_string(),
+        last_response,
+        external_address: gateway.external_address(),
+        code: failure.as_ref().map(|e| error::classify(e).1),
+        error: failure.map(|e| e.to_string()),
+    }
+}
+
 async fn forward(
     State(state): State<AppState>,
+    ConnectInfo(client_address): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
     Json(payload): Json<ForwardRequest>,
-) -> Result<Json<ForwardResponse>, (StatusCode, Json<ErrorResponse>)> {
+) -> Result<Json<ForwardResponse>, ApiError> {
+    let protocol = match payload.protocol.to_lowercase().as_str() {
+        "tcp" => "tcp",
+        "udp" => "udp",
+        _ => "invalid",
+    };
+    // Requests for an unknown gateway are counted without one
+    let gateway = state
+        .gateway(payload.gateway.as_deref())
+        .map_or_else(|_| String::new(), |(name, _)| name);
+    let result = create_mapping(state, client_address, headers, payload).await;
+    let outcome = match &result {
+        Ok(_) => "ok",
+        Err(e) => e.code(),
+    };
+    METRICS
+        .forward_requests
+        .with_label_values(&[&gateway, protocol, outcome])
+        .inc();
+    result
+}
+
+async fn create_mapping(
+    state: AppState,
+    client_address: SocketAddr,
+    headers: HeaderMap,
+    payload: ForwardRequest,
+) -> Result<Json<ForwardResponse>, ApiError> {
     // Check authorization
     if !check_authorization(&headers, &state.token) {
-        return Err((
-            StatusCode::UNAUTHORIZED,
-            Json(ErrorResponse {
-                error: "Unauthorized".to_string(),
-            }),
-        ));
+        return Err(ApiError::unauthorized());
     }
 
     // Validate and clamp duration
@@ -113,106 +873,915 @@ async fn forward(
         None => payload.duration, // No limit if max_duration is -1
     };
 
-    // Convert IpAddr to Ipv4Addr
-    let gateway_v4 = match state.gateway {
-        IpAddr::V4(ipv4) => ipv4,
-        IpAddr::V6(_) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: "IPv6 gateways not supported".to_string(),
-                }),
+    let protocol_enum = parse_protocol(&payload.protocol)?;
+    let (name, gateway) = state.gateway(payload.gateway.as_deref())?;
+
+    if payload.require_exact && payload.external_port.is_none() {
+        return Err(ApiError::bad_request(
+            "require_exact needs an external_port",
+        ));
+    }
+    if let Some(template) = &payload.on_change {
+        let Some(hooks) = &state.hooks else {
+            return Err(ApiError::bad_request(
+                "on_change hooks are disabled (see --hook-dir)",
             ));
-        }
+        };
+        hooks.parse(template).map_err(ApiError::bad_request)?;
+    }
+    if let Some(name) = &payload.port_file {
+        let Some(port_files) = &state.port_files else {
+            return Err(ApiError::bad_request(
+                "port_file is disabled (see --port-file-dir)",
+            ));
+        };
+        port_files.check(name).map_err(ApiError::bad_request)?;
+    }
+
+    let key = MappingKey {
+        gateway: name,
+        protocol: payload.protocol.to_lowercase(),
+        internal_port: payload.internal_port,
+        internal_address: payload.internal_address,
+        peer: payload.peer,
     };
 
-    // Create NAT-PMP client
-    let mut client = match Natpmp::new_with(gateway_v4) {
-        Ok(client) => client,
-        Err(e) => {
-            error!("Failed to create NAT-PMP client: {}", e);
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: "Failed to create NAT-PMP client".to_string(),
-                }),
+    // Two mappings writing one file would overwrite it, and releasing either deletes it
+    if let Some(name) = &payload.port_file {
+        let mut mappings = state.mappings.lock().unwrap();
+        prune_expired(&state, &mut mappings);
+        let owner = mappings
+            .iter()
+            .find(|(other, entry)| **other != key && entry.port_file.as_ref() == Some(name));
+        if let Some((owner, _)) = owner {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                "port_file_in_use",
+                format!("port_file {} is used by mapping {}", name, owner),
             ));
         }
+    }
+
+    // Heartbeats ask for the port the mapping already has, so it survives gateway resets
+    let external_port = payload.external_port.unwrap_or_else(|| {
+        let mappings = state.mappings.lock().unwrap();
+        mappings.get(&key).map_or(0, |entry| entry.external_port)
+    });
+    let request = Request {
+        protocol: protocol_enum,
+        internal_port: payload.internal_port,
+        internal_address: payload.internal_address,
+        peer: payload.peer,
+        external_port,
+        lifetime: duration,
     };
 
-    // Request port mapping (validates protocol implicitly)
-    let protocol_enum = match payload.protocol.to_lowercase().as_str() {
-        "tcp" => Protocol::TCP,
-        "udp" => Protocol::UDP,
-        _ => {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                Json(ErrorResponse {
-                    error: "protocol must be tcp or udp".to_string(),
-                }),
-            ));
+    let mapping = request_mapping(&gateway, &request)
+        .await
+        .map_err(|e| ApiError::gateway("Port mapping request failed", &e))?;
+
+    if payload.require_exact && duration > 0 && mapping.external_port != external_port {
+        warn!(
+            "Gateway assigned {} to {} instead of {}, deleting mapping",
+            mapping.external_port, key, external_port
+        );
+        release_lease(&state, &key);
+        forget_mapping(&state, &key);
+        persist(&state);
+        delete_mapping(&gateway, &request).await;
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "port_unavailable",
+            format!(
+                "External port {} is not available (gateway assigned {})",
+                external_port, mapping.external_port
+            ),
+        ));
+    }
+
+    info!(
+        "Created mapping: {} -> {} (duration: {}s)",
+        key, mapping.external_port, duration
+    );
+
+    if duration == 0 {
+        // A zero lifetime deletes the mapping on the gateway, so stop renewing it too
+        release_lease(&state, &key);
+        forget_mapping(&state, &key);
+    } else {
+        let mut mappings = state.mappings.lock().unwrap();
+        let previous = mappings.remove(&key);
+        let previous_port = previous.as_ref().map(|entry| entry.external_port);
+        // A renamed port file should not leave the old one behind
+        if let Some(old) = previous.as_ref().and_then(|entry| entry.port_file.as_ref()) {
+            if payload.port_file.as_ref() != Some(old) {
+                remove_port_file(&state, old);
+            }
         }
+        let mut lease = previous.and_then(|entry| entry.lease);
+        if let Some(keepalive) = payload.keepalive {
+            if let Some(old) = lease.take() {
+                old.task.abort();
+            }
+            let task = tokio::spawn(renew_lease(state.clone(), key.clone(), mapping.lifetime));
+            lease = Some(Lease {
+                keepalive: (keepalive > 0).then(|| Duration::from_secs(keepalive.into())),
+                last_seen: Instant::now(),
+                task,
+            });
+        } else if let Some(lease) = lease.as_mut() {
+            // Plain heartbeats for a managed mapping still count as keepalives
+            lease.last_seen = Instant::now();
+        }
+        mappings.insert(
+            key.clone(),
+            MappingEntry {
+                gateway: gateway.clone(),
+                request,
+                external_port: mapping.external_port,
+                lifetime: mapping.lifetime,
+                expires_at: expires_at(mapping.lifetime),
+                client_address,
+                token_id: token_id(&state.token),
+                require_exact: payload.require_exact,
+                lease,
+                on_change: payload.on_change,
+                port_file: payload.port_file,
+                port_file_format: payload.port_file_format,
+            },
+        );
+        mapping_updated(&state, &key, &mappings[&key], previous_port);
+    }
+    persist(&state);
+
+    let external_address = match mapping.external_address {
+        Some(address) => Some(address),
+        None => gateway.resolve_external_address().await,
     };
 
-    // Send the request
-    if let Err(e) = client.send_port_mapping_request(
-        protocol_enum,
-        payload.internal_port,
-        0, // Let NAT-PMP choose external port
+    Ok(Json(ForwardResponse {
+        internal_port: payload.internal_port,
+        external_port: mapping.external_port,
+        protocol: key.protocol,
         duration,
-    ) {
-        error!("Failed to send port mapping request: {}", e);
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: "Failed to send port mapping request".to_string(),
-            }),
-        ));
+        external_address,
+        via: (gateway.name != key.gateway).then(|| gateway.name.clone()),
+        gateway: key.gateway,
+    }))
+}
+
+async fn release(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<ReleaseRequest>,
+) -> Result<Json<ReleaseResponse>, ApiError> {
+    // Check authorization
+    if !check_authorization(&headers, &state.token) {
+        return Err(ApiError::unauthorized());
     }
 
-    // Wait a bit for the response
-    tokio::time::sleep(tokio::time::Duration::from_millis(250)).await;
-
-    // Read the response
-    match client.read_response_or_retry() {
-        Ok(response) => {
-            let external_port = match response {
-                Response::UDP(ur) => ur.public_port(),
-                Response::TCP(tr) => tr.public_port(),
-                _ => {
-                    error!("Unexpected response type");
-                    return Err((
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        Json(ErrorResponse {
-                            error: "Unexpected response type".to_string(),
-                        }),
-                    ));
+    let protocol_enum = parse_protocol(&payload.protocol)?;
+    let (name, mut gateway) = state.gateway(payload.gateway.as_deref())?;
+    let key = MappingKey {
+        gateway: name,
+        protocol: payload.protocol.to_lowercase(),
+        internal_port: payload.internal_port,
+        internal_address: payload.internal_address,
+        peer: payload.peer,
+    };
+
+    // Stop renewing first so a renewal cannot race the delete request
+    release_lease(&state, &key);
+    let mut request = match state.mappings.lock().unwrap().get(&key) {
+        // The mapping may still be on a failover group's previous gateway
+        Some(entry) => {
+            gateway = entry.gateway.clone();
+            entry.request.clone()
+        }
+        None => Request {
+            protocol: protocol_enum,
+            internal_port: payload.internal_port,
+            internal_address: payload.internal_address,
+            peer: payload.peer,
+            external_port: 0,
+            lifetime: 0,
+        },
+    };
+
+    // RFC 6886 section 3.4 and RFC 6887 section 15: a request with lifetime 0 deletes the mapping
+    request.lifetime = 0;
+    let mapping = request_mapping(&gateway, &request)
+        .await
+        .map_err(|e| ApiError::gateway("Port mapping request failed", &e))?;
+
+    // The gateway acknowledges a delete with a zero lifetime
+    let removed = mapping.lifetime == 0;
+    if removed {
+        forget_mapping(&state, &key);
+        persist(&state);
+        info!("Deleted mapping: {}", key);
+    } else {
+        warn!(
+            "Gateway did not confirm deletion of {} (external port: {}, lifetime: {}s)",
+            key, mapping.external_port, mapping.lifetime
+        );
+    }
+
+    Ok(Json(ReleaseResponse {
+        internal_port: payload.internal_port,
+        protocol: key.protocol,
+        removed,
+        gateway: key.gateway,
+    }))
+}
+
+async fn list_mappings(
+    State(state): State<AppState>,
+    Query(query): Query<GatewayQuery>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<MappingInfo>>, ApiError> {
+    // Check authorization
+    if !check_authorization(&headers, &state.token) {
+        return Err(ApiError::unauthorized());
+    }
+
+    if let Some(name) = &query.gateway {
+        state.gateway(Some(name))?;
+    }
+
+    let mut mappings = state.mappings.lock().unwrap();
+    prune_expired(&state, &mut mappings);
+
+    let mut list: Vec<MappingInfo> = mappings
+        .iter()
+        .filter(|(key, _)| {
+            query
+                .gateway
+                .as_ref()
+                .is_none_or(|name| key.gateway == *name)
+        })
+        .map(|(key, entry)| MappingInfo::new(key, entry))
+        .collect();
+    list.sort_by(|a, b| {
+        (
+            &a.gateway,
+            &a.protocol,
+            a.internal_port,
+            a.internal_address,
+            a.peer,
+        )
+            .cmp(&(
+                &b.gateway,
+                &b.protocol,
+                b.internal_port,
+                b.internal_address,
+                b.peer,
+            ))
+    });
+
+    Ok(Json(list))
+}
+
+async fn get_mapping(
+    State(state): State<AppState>,
+    Path((protocol, internal_port)): Path<(String, u16)>,
+    Query(query): Query<MappingQuery>,
+    headers: HeaderMap,
+) -> Result<Json<MappingInfo>, ApiError> {
+    // Check authorization
+    if !check_authorization(&headers, &state.token) {
+        return Err(ApiError::unauthorized());
+    }
+
+    parse_protocol(&protocol)?;
+    let (name, _) = state.gateway(query.gateway.as_deref())?;
+    let key = MappingKey {
+        gateway: name,
+        protocol: protocol.to_lowercase(),
+        internal_port,
+        internal_address: query.internal_address,
+        peer: query.peer,
+    };
+
+    let mut mappings = state.mappings.lock().unwrap();
+    prune_expired(&state, &mut mappings);
+
+    match mappings.get(&key) {
+        Some(entry) => Ok(Json(MappingInfo::new(&key, entry))),
+        None => Err(ApiError::not_found("Mapping not found")),
+    }
+}
+
+async fn external_address(
+    State(state): State<AppState>,
+    Query(query): Query<GatewayQuery>,
+    headers: HeaderMap,
+) -> Result<Json<ExternalAddressResponse>, ApiError> {
+    // Check authorization
+    if !check_authorization(&headers, &state.token) {
+        return Err(ApiError::unauthorized());
+    }
+
+    let (name, gateway) = state.gateway(query.gateway.as_deref())?;
+    let status = gateway.probe().await.map_err(|e| {
+        error!("Public address request to {} failed: {}", gateway, e);
+        ApiError::gateway("Public address request failed", &e)
+    })?;
+
+    match status.external_address {
+        Some(external_address) => Ok(Json(ExternalAddressResponse {
+            gateway: name,
+            external_address,
+            epoch: status.epoch,
+        })),
+        // PCP only reports the address along with a mapping
+        None => Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "address_unknown",
+            "Public address not known yet, create a mapping first",
+        )),
+    }
+}
+
+/// Streams changes to mappings and gateways as server-sent events, optionally only
+/// those of one mapping or gateway
+async fn stream_events(
+    State(state): State<AppState>,
+    Query(query): Query<EventQuery>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, ApiError> {
+    // Check authorization
+    if !check_authorization(&headers, &state.token) {
+        return Err(ApiError::unauthorized());
+    }
+
+    if let Some(protocol) = &query.protocol {
+        parse_protocol(protocol)?;
+    }
+    let gateways = match &query.gateway {
+        None => None,
+        Some(name) => {
+            state.gateway(Some(name))?;
+            // A group's events may name the group or the member holding its mappings
+            let mut names = vec![name.clone()];
+            if let Some(group) = state.groups.iter().find(|group| group.name == *name) {
+                names.extend(group.members.iter().map(|member| member.name.clone()));
+            }
+            Some(names)
+        }
+    };
+    let filter = Filter {
+        internal_port: query.internal_port,
+        protocol: query.protocol.map(|protocol| protocol.to_lowercase()),
+        gateways,
+    };
+
+    let stream = state
+        .events
+        .subscribe()
+        .filter_map(move |event| match event {
+            Ok(event) if filter.matches(&event) => sse::Event::default()
+                .event(event.change.kind())
+                .json_data(&*event)
+                .ok(),
+            Ok(_) => None,
+            // Tell slow clients to resynchronize from /mappings
+            Err(missed) => Some(
+                sse::Event::default()
+                    .event("lagged")
+                    .data(missed.to_string()),
+            ),
+        })
+        .map(Ok);
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Sends a port mapping request to the gateway and waits for its response
+async fn request_mapping(
+    gateway: &Arc<Gateway>,
+    request: &Request,
+) -> Result<Mapping, gateway::Error> {
+    match gateway.map(request).await {
+        Ok(mapping) => {
+            match mapping.external_address {
+                Some(address) => debug!(
+                    "Gateway epoch: {}s, external address: {}",
+                    mapping.epoch, address
+                ),
+                None => debug!("Gateway epoch: {}s", mapping.epoch),
+            }
+            Ok(mapping)
+        }
+        Err(e) => {
+            error!("Port mapping request failed: {}", e);
+            Err(e)
+        }
+    }
+}
+
+enum Renewal {
+    Updated,
+    /// The mapping was released in the meantime
+    Gone,
+    /// The gateway moved a mapping that must keep its port, so it was dropped
+    Moved,
+}
+
+/// Stores the outcome of re-requesting a known mapping
+fn record_renewal(state: &AppState, key: &MappingKey, mapping: &Mapping) -> Renewal {
+    let mut mappings = state.mappings.lock().unwrap();
+    let Some(entry) = mappings.get_mut(key) else {
+        return Renewal::Gone;
+    };
+
+    if entry.external_port != mapping.external_port {
+        if entry.require_exact {
+            warn!(
+                "Renewed mapping {} moved to {}, deleting it",
+                key, mapping.external_port
+            );
+            // Stop the lease, unless this is its own renewal, which still has to send the delete
+            if let Some(lease) = entry.lease.take() {
+                if tokio::task::try_id() != Some(lease.task.id()) {
+                    lease.task.abort();
                 }
+            }
+            if let Some(entry) = mappings.remove(key) {
+                mapping_removed(state, key, &entry, Change::MappingDeleted);
+            }
+            persist(state);
+            return Renewal::Moved;
+        }
+        warn!(
+            "Renewed mapping {} moved: {} -> {}",
+            key, entry.external_port, mapping.external_port
+        );
+    }
+    let previous_port = std::mem::replace(&mut entry.external_port, mapping.external_port);
+    entry.lifetime = mapping.lifetime;
+    entry.expires_at = expires_at(mapping.lifetime);
+    mapping_updated(state, key, entry, Some(previous_port));
+    persist(state);
+    Renewal::Updated
+}
+
+/// Best-effort removal of a mapping the gateway should not keep
+async fn delete_mapping(gateway: &Arc<Gateway>, request: &Request) {
+    let request = Request {
+        lifetime: 0,
+        ..request.clone()
+    };
+    if let Err(e) = request_mapping(gateway, &request).await {
+        warn!("Failed to delete mapping: {}", e);
+    }
+}
+
+/// Keeps a managed mapping alive by re-requesting it at half of the granted
+/// lifetime, until the lease is released or the client's keepalive lapses
+async fn renew_lease(state: AppState, key: MappingKey, mut lifetime: u32) {
+    loop {
+        tokio::time::sleep(Duration::from_secs((lifetime / 2).max(1).into())).await;
+
+        let (gateway, request) = {
+            let mut mappings = state.mappings.lock().unwrap();
+            let Some(entry) = mappings.get_mut(&key) else {
+                return;
+            };
+            let Some(lease) = entry.lease.as_ref() else {
+                return;
+            };
+            if lease.lapsed() {
+                entry.lease = None;
+                persist(&state);
+                info!("Lease for {} lapsed, letting mapping expire", key);
+                return;
+            }
+            // RFC 6886 section 3.3: renewals suggest the port that was assigned
+            let request = Request {
+                external_port: entry.external_port,
+                ..entry.request.clone()
             };
+            (entry.gateway.clone(), request)
+        };
 
-            info!(
-                "Created mapping: {}/{} -> {} (duration: {}s)",
-                payload.internal_port,
-                payload.protocol.to_lowercase(),
-                external_port,
-                duration
+        match request_mapping(&gateway, &request).await {
+            Ok(mapping) => {
+                lifetime = mapping.lifetime;
+                match record_renewal(&state, &key, &mapping) {
+                    Renewal::Updated => {}
+                    Renewal::Gone => return,
+                    Renewal::Moved => {
+                        delete_mapping(&gateway, &request).await;
+                        return;
+                    }
+                }
+                debug!(
+                    "Renewed mapping: {} -> {} (duration: {}s)",
+                    key, mapping.external_port, lifetime
+                );
+            }
+            Err(e) => {
+                // Retry sooner so the mapping does not expire while the gateway is flaky
+                warn!("Failed to renew mapping {}: {}", key, e);
+                lifetime /= 2;
+            }
+        }
+    }
+}
+
+/// Re-creates the gateway's known mappings whenever it turns out to have lost them,
+/// instead of waiting for the next renewal or client heartbeat
+async fn remap_on_reset(state: AppState, gateway: Arc<Gateway>) {
+    loop {
+        gateway.reset().await;
+        let count = state
+            .mappings
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|entry| Arc::ptr_eq(&gateway, &entry.gateway))
+            .count();
+        info!(
+            "Gateway {} reset, re-creating {} mapping(s)",
+            gateway, count
+        );
+        remap_all(&state, Some(&gateway)).await;
+    }
+}
+
+/// The request that puts a known mapping back in place with the port it had.
+/// Managed leases get their full lifetime, other mappings only the time their
+/// client still expects.
+fn reassert_request(entry: &MappingEntry, now: DateTime<Utc>) -> Request {
+    let lifetime = match entry.lease {
+        Some(_) => entry.request.lifetime,
+        None => {
+            let remaining = (entry.expires_at - now).num_seconds().max(1);
+            u32::try_from(remaining).unwrap_or(u32::MAX)
+        }
+    };
+    Request {
+        external_port: entry.external_port,
+        lifetime,
+        ..entry.request.clone()
+    }
+}
+
+/// Requests every known mapping (on `only`, if given) again with the port it had
+async fn remap_all(state: &AppState, only: Option<&Arc<Gateway>>) {
+    let requests: Vec<(MappingKey, Arc<Gateway>, Request)> = {
+        let mut mappings = state.mappings.lock().unwrap();
+        prune_expired(state, &mut mappings);
+        let now = Utc::now();
+        mappings
+            .iter()
+            .filter(|(_, entry)| only.is_none_or(|gateway| Arc::ptr_eq(gateway, &entry.gateway)))
+            .map(|(key, entry)| {
+                (
+                    key.clone(),
+                    entry.gateway.clone(),
+                    reassert_request(entry, now),
+                )
+            })
+            .collect()
+    };
+
+    for (key, gateway, request) in requests {
+        match request_mapping(&gateway, &request).await {
+            Ok(mapping) => match record_renewal(state, &key, &mapping) {
+                Renewal::Updated => info!(
+                    "Re-created mapping: {} -> {} (duration: {}s)",
+                    key, mapping.external_port, mapping.lifetime
+                ),
+                Renewal::Gone => {}
+                Renewal::Moved => delete_mapping(&gateway, &request).await,
+            },
+            Err(e) => warn!("Failed to re-create mapping {}: {}", key, e),
+        }
+    }
+}
+
+/// Notices mappings whose lifetime ran out soon after it happens, so subscribers to
+/// /events hear about it
+async fn expire_mappings(state: AppState) {
+    let mut checks = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        checks.tick().await;
+        let mut mappings = state.mappings.lock().unwrap();
+        prune_expired(&state, &mut mappings);
+    }
+}
+
+/// Watches a failover group's active gateway and, once it failed `--failover-checks`
+/// checks in a row, moves the group's mappings to the first other member that answers. The group stays on the new
+/// gateway until that one fails too, so a flapping tunnel does not move ports back and forth.
+async fn fail_over(state: AppState, group: Arc<FailoverGroup>) {
+    let mut checks = tokio::time::interval(state.failover_interval);
+    checks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut failures = 0;
+    loop {
+        checks.tick().await;
+        let active = group.active();
+        let fresh = active
+            .last_response()
+            .is_some_and(|at| at.elapsed() <= state.failover_interval);
+        let failure = if fresh {
+            None
+        } else {
+            active.probe_within(state.ready_timeout).await.err()
+        };
+        let Some(e) = failure else {
+            failures = 0;
+            // Mappings the active gateway refused at the switch are retried until it takes them
+            move_mappings(&state, &group.name, &active).await;
+            continue;
+        };
+        failures += 1;
+        if failures < state.failover_checks {
+            warn!(
+                "Gateway {} of failover group {} failed check {}/{}: {}",
+                active, group.name, failures, state.failover_checks, e
             );
+            continue;
+        }
+
+        warn!(
+            "Gateway {} of failover group {} stopped answering: {}",
+            active, group.name, e
+        );
+        let Some(next) = group.find_healthy(state.ready_timeout).await else {
+            warn!(
+                "No other gateway of failover group {} answers, staying on {}",
+                group.name, active
+            );
+            continue;
+        };
+        group.set_active(next.clone());
+        failures = 0;
+        METRICS
+            .failovers
+            .with_label_values(&[&group.name, &active.name, &next.name])
+            .inc();
+        info!(
+            "Failover group {} switched from {} to {}",
+            group.name, active, next
+        );
+        move_mappings(&state, &group.name, &next).await;
+    }
+}
 
-            Ok(Json(ForwardResponse {
-                internal_port: payload.internal_port,
-                external_port,
-                protocol: payload.protocol.to_lowercase(),
-                duration,
-            }))
+/// Re-creates a failover group's mappings on the gateway that took over, asking for
+/// the ports they had. The gateway left behind keeps its copies until they expire,
+/// and mappings that fail to move stay on it until the next attempt.
+async fn move_mappings(state: &AppState, group: &str, gateway: &Arc<Gateway>) {
+    let requests: Vec<(MappingKey, Request)> = {
+        let mut mappings = state.mappings.lock().unwrap();
+        prune_expired(state, &mut mappings);
+        let now = Utc::now();
+        mappings
+            .iter()
+            .filter(|(key, entry)| key.gateway == group && !Arc::ptr_eq(&entry.gateway, gateway))
+            .map(|(key, entry)| (key.clone(), reassert_request(entry, now)))
+            .collect()
+    };
+    if requests.is_empty() {
+        return;
+    }
+    info!(
+        "Moving {} mapping(s) of {} to {}",
+        requests.len(),
+        group,
+        gateway
+    );
+
+    for (key, request) in requests {
+        match request_mapping(gateway, &request).await {
+            Ok(mapping) => {
+                if let Some(entry) = state.mappings.lock().unwrap().get_mut(&key) {
+                    entry.gateway = gateway.clone();
+                }
+                match record_renewal(state, &key, &mapping) {
+                    Renewal::Updated => info!(
+                        "Moved mapping {} to {}: external port {} (duration: {}s)",
+                        key, gateway, mapping.external_port, mapping.lifetime
+                    ),
+                    Renewal::Gone => {}
+                    Renewal::Moved => delete_mapping(gateway, &request).await,
+                }
+            }
+            Err(e) => warn!("Failed to move mapping {} to {}: {}", key, gateway, e),
         }
-        Err(e) => {
-            error!("Failed to read port mapping response: {}", e);
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: "Failed to read port mapping response".to_string(),
-                }),
-            ))
+    }
+}
+
+/// Deletes every known mapping on the gateway, giving up on those not confirmed
+/// within `timeout`
+async fn release_all(state: &AppState, timeout: Duration) {
+    let requests: Vec<(MappingKey, Arc<Gateway>, Request)> = {
+        let mut mappings = state.mappings.lock().unwrap();
+        prune_expired(state, &mut mappings);
+        mappings
+            .iter_mut()
+            .map(|(key, entry)| {
+                if let Some(lease) = entry.lease.take() {
+                    lease.task.abort();
+                }
+                let request = Request {
+                    lifetime: 0,
+                    ..entry.request.clone()
+                };
+                (key.clone(), entry.gateway.clone(), request)
+            })
+            .collect()
+    };
+    if requests.is_empty() {
+        return;
+    }
+
+    let total = requests.len();
+    info!("Releasing {} mapping(s) before shutdown", total);
+
+    let mut tasks = JoinSet::new();
+    for (key, gateway, request) in requests {
+        tasks.spawn(async move {
+            let result = request_mapping(&gateway, &request).await;
+            (key, result)
+        });
+    }
+
+    let mut released = 0;
+    let finished = tokio::time::timeout(timeout, async {
+        while let Some(Ok((key, result))) = tasks.join_next().await {
+            match result {
+                Ok(mapping) if mapping.lifetime == 0 => {
+                    forget_mapping(state, &key);
+                    released += 1;
+                    info!("Deleted mapping: {}", key);
+                }
+                Ok(_) => warn!("Gateway did not confirm deletion of {}", key),
+                Err(e) => warn!("Failed to delete mapping {}: {}", key, e),
+            }
+        }
+    })
+    .await;
+
+    if finished.is_err() {
+        warn!(
+            "Gave up waiting for the gateway after {}s",
+            timeout.as_secs()
+        );
+    }
+    if released == total {
+        info!("Released all {} mapping(s)", total);
+    } else {
+        warn!(
+            "Released {} of {} mapping(s), the rest stay open until they expire",
+            released, total
+        );
+    }
+
+    // Write directly, the background writer will not get to run anymore
+    if let Some(state_file) = &state.state_file {
+        if let Err(e) = state_file.save(&snapshot(state)).await {
+            error!(
+                "Failed to write state file {}: {}",
+                state_file.path().display(),
+                e
+            );
+        }
+    }
+}
+
+/// Captures the known mappings for the state file
+fn snapshot(state: &AppState) -> Snapshot {
+    let mappings = state.mappings.lock().unwrap();
+    let now = Utc::now();
+    let mappings = mappings
+        .iter()
+        .map(|(key, entry)| SavedMapping {
+            gateway: key.gateway.clone(),
+            protocol: key.protocol.clone(),
+            internal_port: key.internal_port,
+            internal_address: key.internal_address,
+            peer: entry.request.peer,
+            external_port: entry.external_port,
+            duration: entry.request.lifetime,
+            expires_at: entry.expires_at,
+            client_address: entry.client_address,
+            token_id: entry.token_id.clone(),
+            require_exact: entry.require_exact,
+            on_change: entry.on_change.clone(),
+            port_file: entry.port_file.clone(),
+            port_file_format: entry.port_file_format,
+            lease: entry.lease.as_ref().map(|lease| SavedLease {
+                keepalive: lease.keepalive.map(|keepalive| keepalive.as_secs() as u32),
+                last_seen: now
+                    - chrono::Duration::from_std(lease.last_seen.elapsed()).unwrap_or_default(),
+            }),
+        })
+        .collect();
+    let nonces = state
+        .gateways
+        .iter()
+        .map(|gateway| (gateway.name.clone(), gateway.nonce()))
+        .collect();
+    Snapshot { nonces, mappings }
+}
+
+/// Writes the state file whenever the known mappings change
+async fn write_state(state: AppState, state_file: Arc<StateFile>) {
+    loop {
+        state_file.changed().await;
+        let snapshot = snapshot(&state);
+        if let Err(e) = state_file.save(&snapshot).await {
+            error!(
+                "Failed to write state file {}: {}",
+                state_file.path().display(),
+                e
+            );
+        }
+    }
+}
+
+/// Puts the mappings of a previous run back in place, restarting their leases
+fn restore(state: &AppState, saved: Vec<SavedMapping>) {
+    let now = Utc::now();
+    let mut mappings = state.mappings.lock().unwrap();
+    for saved in saved {
+        let Ok(protocol) = parse_protocol(&saved.protocol) else {
+            warn!("Skipping saved mapping with protocol {}", saved.protocol);
+            continue;
+        };
+        let Ok((name, gateway)) = state.gateway(Some(&saved.gateway)) else {
+            warn!(
+                "Skipping saved mapping for {}/{} on unknown gateway {}",
+                saved.internal_port, saved.protocol, saved.gateway
+            );
+            continue;
+        };
+        let key = MappingKey {
+            gateway: name,
+            protocol: saved.protocol.to_lowercase(),
+            internal_port: saved.internal_port,
+            internal_address: saved.internal_address,
+            peer: saved.peer,
+        };
+
+        let lease = saved.lease.map(|lease| {
+            let silence = (now - lease.last_seen).to_std().unwrap_or_default();
+            Lease {
+                keepalive: lease
+                    .keepalive
+                    .map(|keepalive| Duration::from_secs(keepalive.into())),
+                last_seen: Instant::now()
+                    .checked_sub(silence)
+                    .unwrap_or_else(Instant::now),
+                task: tokio::spawn(renew_lease(state.clone(), key.clone(), saved.duration)),
+            }
+        });
+        mappings.insert(
+            key,
+            MappingEntry {
+                gateway: gateway.clone(),
+                request: Request {
+                    protocol,
+                    internal_port: saved.internal_port,
+                    internal_address: saved.internal_address,
+                    peer: saved.peer,
+                    external_port: saved.external_port,
+                    lifetime: saved.duration,
+                },
+                external_port: saved.external_port,
+                lifetime: saved.duration,
+                expires_at: saved.expires_at,
+                client_address: saved.client_address,
+                token_id: saved.token_id,
+                require_exact: saved.require_exact,
+                lease,
+                on_change: saved.on_change,
+                port_file: saved.port_file,
+                port_file_format: saved.port_file_format,
+            },
+        );
+    }
+}
+
+/// The mapping an application integration follows and the name it is kept under,
+/// exiting when `--{app}-mapping` does not name one
+fn followed_mapping(
+    state: &AppState,
+    app: &str,
+    mapping: Option<MappingSpec>,
+) -> (MappingSpec, String) {
+    let Some(mapping) = mapping else {
+        error!("--{}-url needs --{}-mapping", app, app);
+        std::process::exit(1);
+    };
+    match state.gateway(mapping.gateway.as_deref()) {
+        Ok((name, _)) => (mapping, name),
+        Err(_) => {
+            error!("Unknown gateway in --{}-mapping {}", app, mapping);
+            std::process::exit(1);
         }
     }
 }
@@ -235,46 +1804,358 @@ async fn main() {
         )
         .init();
 
+    for (i, spec) in args.gateway.iter().enumerate() {
+        if args.gateway[..i]
+            .iter()
+            .any(|other| other.name == spec.name)
+        {
+            error!(
+                "Gateway name {} is used more than once, name them with --gateway name=address",
+                spec.name
+            );
+            std::process::exit(1);
+        }
+        let ipv6 = matches!(spec.address, GatewayAddress::Fixed(address) if address.is_ipv6());
+        if ipv6 && args.protocol == Backend::Natpmp {
+            error!(
+                "NAT-PMP does not support IPv6 gateways like {}, use --protocol pcp",
+                spec.name
+            );
+            std::process::exit(1);
+        }
+    }
+
+    // Look up the gateways set to `auto`, along with the interface to keep following
+    let mut routed = Vec::new();
+    let mut addresses = Vec::new();
+    for spec in &args.gateway {
+        let interface = match &spec.address {
+            GatewayAddress::Fixed(address) => {
+                addresses.push(*address);
+                continue;
+            }
+            GatewayAddress::Route(interface) => {
+                interface.clone().or(args.gateway_interface.clone())
+            }
+        };
+        match route::find_gateway(interface.as_deref()) {
+            Ok(Some(address)) => {
+                match &interface {
+                    Some(interface) => info!(
+                        "Gateway {} is {} (route through {})",
+                        spec.name, address, interface
+                    ),
+                    None => info!("Gateway {} is {} (default route)", spec.name, address),
+                }
+                addresses.push(IpAddr::V4(address));
+                routed.push((spec.name.clone(), interface));
+            }
+            Ok(None) => {
+                match &interface {
+                    Some(interface) => error!(
+                        "No route or address on {} to take gateway {} from, set its address instead",
+                        interface, spec.name
+                    ),
+                    None => error!(
+                        "No default route to take gateway {} from, set its address instead",
+                        spec.name
+                    ),
+                }
+                std::process::exit(1);
+            }
+            Err(e) => {
+                error!("Failed to read the routing table: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if args.gateway_interface.is_some() && routed.is_empty() {
+        error!("--gateway-interface only applies to --gateway auto");
+        std::process::exit(1);
+    }
+
+    let state_file = args.state_file.map(|path| Arc::new(StateFile::new(path)));
+    let saved = match state_file.as_ref().map(|state_file| state_file.load()) {
+        Some(Ok(saved)) => saved,
+        Some(Err(e)) => {
+            error!("Failed to read state file: {}", e);
+            std::process::exit(1);
+        }
+        None => None,
+    };
+
+    let hooks = match &args.hook_dir {
+        Some(dir) => match Hooks::new(dir, Duration::from_secs(args.hook_timeout)) {
+            Ok(hooks) => Some(Arc::new(hooks)),
+            Err(e) => {
+                error!("Invalid --hook-dir {}: {}", dir.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let port_files = match &args.port_file_dir {
+        Some(dir) => match PortFiles::new(dir) {
+            Ok(port_files) => Some(Arc::new(port_files)),
+            Err(e) => {
+                error!("Invalid --port-file-dir {}: {}", dir.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let events = EventBus::new();
+    let gateways: Vec<Arc<Gateway>> = args
+        .gateway
+        .iter()
+        .zip(addresses)
+        .map(|(spec, address)| {
+            let nonce = saved
+                .as_ref()
+                .and_then(|saved| saved.nonces.get(&spec.name).copied());
+            Arc::new(Gateway::new(
+                spec.name.clone(),
+                address,
+                args.protocol,
+                nonce,
+                events.clone(),
+            ))
+        })
+        .collect();
+
+    let mut groups: Vec<Arc<FailoverGroup>> = Vec::new();
+    for spec in &args.failover_group {
+        let taken = gateways.iter().any(|gateway| gateway.name == spec.name)
+            || groups.iter().any(|group| group.name == spec.name);
+        if taken {
+            error!(
+                "Failover group name {} is already used by a gateway or group",
+                spec.name
+            );
+            std::process::exit(1);
+        }
+        let mut members = Vec::new();
+        for name in &spec.members {
+            match gateways.iter().find(|gateway| gateway.name == *name) {
+                Some(gateway) => members.push(gateway.clone()),
+                None => {
+                    error!(
+                        "Failover group {} names unknown gateway {}",
+                        spec.name, name
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }
+        groups.push(Arc::new(FailoverGroup::new(spec.name.clone(), members)));
+    }
+
+    let default_gateway = match args.default_gateway {
+        Some(name) => {
+            let known = gateways.iter().any(|gateway| gateway.name == name)
+                || groups.iter().any(|group| group.name == name);
+            if !known {
+                error!(
+                    "Default gateway {} is not one of the --gateway or --failover-group names",
+                    name
+                );
+                std::process::exit(1);
+            }
+            name
+        }
+        None => gateways[0].name.clone(),
+    };
+
     let state = AppState {
-        gateway: args.gateway,
+        gateways: Arc::new(gateways),
+        groups: Arc::new(groups),
+        default_gateway,
         max_duration: if args.max_duration == -1 {
             None
         } else {
             Some(args.max_duration as u32)
         },
         token: std::env::var("NATPMP_TOKEN").ok(),
+        mappings: Arc::new(Mutex::new(HashMap::new())),
+        state_file: state_file.clone(),
+        events: events.clone(),
+        hooks,
+        port_files,
+        ready_max_age: Duration::from_secs(args.ready_max_age),
+        ready_timeout: Duration::from_secs(args.ready_timeout),
+        failover_interval: Duration::from_secs(args.failover_interval),
+        failover_checks: args.failover_checks.max(1),
+        restoring: Arc::new(AtomicBool::new(saved.is_some())),
     };
 
+    // Webhooks and integrations share one client, and with it their connections
+    let http = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Failed to build HTTP client");
+
+    // Subscribe before restoring, so webhooks hear of ports that moved while we were down
+    for url in args.webhook {
+        info!("Posting changes to webhook {}", url);
+        let webhook = Webhook::new(
+            url,
+            args.webhook_secret.clone(),
+            args.webhook_retries,
+            http.clone(),
+        );
+        tokio::spawn(webhook.run(events.subscribe()));
+    }
+    if state.port_files.is_some() {
+        tokio::spawn(update_port_files(state.clone(), events.subscribe()));
+    }
+
+    if let Some(url) = args.qbittorrent_url {
+        let (mapping, gateway) = followed_mapping(&state, "qbittorrent", args.qbittorrent_mapping);
+        info!("Setting qBittorrent at {} to the port of {}", url, mapping);
+        let credentials = args
+            .qbittorrent_username
+            .map(|username| (username, args.qbittorrent_password.unwrap_or_default()));
+        let qbittorrent = Qbittorrent::new(url, credentials, http.clone());
+        tokio::spawn(integration::follow(
+            qbittorrent,
+            mapping,
+            gateway,
+            events.subscribe(),
+        ));
+    }
+    if let Some(url) = args.transmission_url {
+        let (mapping, gateway) =
+            followed_mapping(&state, "transmission", args.transmission_mapping);
+        info!("Setting Transmission at {} to the port of {}", url, mapping);
+        let credentials = args
+            .transmission_username
+            .map(|username| (username, args.transmission_password.unwrap_or_default()));
+        let transmission = Transmission::new(url, credentials, http.clone());
+        tokio::spawn(integration::follow(
+            transmission,
+            mapping,
+            gateway,
+            events.subscribe(),
+        ));
+    }
+
+    // Re-assert the mappings of the previous run in the background, since a dead gateway
+    // takes minutes to time out; /ready answers 503 until they are in place
+    if let Some(saved) = saved {
+        restore(&state, saved.mappings);
+        let state = state.clone();
+        tokio::spawn(async move {
+            remap_all(&state, None).await;
+            let count = state.mappings.lock().unwrap().len();
+            info!("Restored {} mapping(s) from state file", count);
+            state.restoring.store(false, Ordering::Relaxed);
+        });
+    }
+    if let Some(state_file) = state_file {
+        tokio::spawn(write_state(state.clone(), state_file));
+        persist(&state);
+    }
+
     // Build our application with routes
     let app = Router::new()
         .route("/health", get(health))
-        .route("/forward", post(forward))
+        .route("/ready", get(ready))
+        .route("/metrics", get(metrics))
+        .route("/events", get(stream_events))
+        .route("/forward", post(forward).delete(release))
+        .route("/external-address", get(external_address))
+        .route("/mappings", get(list_mappings))
+        .route("/mappings/{protocol}/{internal_port}", get(get_mapping))
         .layer(
-        TraceLayer::new_for_http()
-            .make_span_with(tower_http::trace::DefaultMakeSpan::new().level(tracing::Level::INFO))
-            .on_request(tower_http::trace::DefaultOnRequest::new().level(tracing::Level::INFO))
-            .on_response(tower_http::trace::DefaultOnResponse::new().level(tracing::Level::INFO))
-    )
-        .with_state(state);
+            TraceLayer::new_for_http()
+                .make_span_with(
+                    tower_http::trace::DefaultMakeSpan::new().level(tracing::Level::INFO),
+                )
+                .on_request(tower_http::trace::DefaultOnRequest::new().level(tracing::Level::INFO))
+                .on_response(
+                    tower_http::trace::DefaultOnResponse::new().level(tracing::Level::INFO),
+                ),
+        )
+        .with_state(state.clone());
 
     let bind_addr = format!("{}:{}", args.bind_address, args.port);
     let listener = TcpListener::bind(&bind_addr).await.unwrap();
 
+    let gateway_list = state
+        .gateways
+        .iter()
+        .map(|gateway| format!("{} via {}", gateway, gateway.backend()))
+        .collect::<Vec<_>>()
+        .join(", ");
     let token_env = std::env::var("NATPMP_TOKEN").ok();
     if token_env.is_some() {
         info!(
             "Starting NAT-PMP server on {} with gateway {} (auth enabled)",
-            bind_addr, args.gateway
+            bind_addr, gateway_list
         );
     } else {
         warn!(
             "Starting NAT-PMP server on {} with gateway {} (no auth - consider using NATPMP_TOKEN)",
-            bind_addr, args.gateway
+            bind_addr, gateway_list
         );
     }
+    if state.gateways.len() > 1 {
+        info!("Default gateway: {}", state.default_gateway);
+    }
+
+    for gateway in state.gateways.iter() {
+        // Probe the gateway in the background so a broken tunnel shows up in the logs early
+        let probed = gateway.clone();
+        tokio::spawn(async move {
+            match probed.probe().await {
+                Ok(status) => match status.external_address {
+                    Some(address) => info!(
+                        "Gateway {} reports public address {} via {} (epoch: {}s)",
+                        probed, address, status.backend, status.epoch
+                    ),
+                    None => info!(
+                        "Gateway {} answered via {} (epoch: {}s)",
+                        probed, status.backend, status.epoch
+                    ),
+                },
+                Err(e) => warn!("Gateway {} did not answer: {}", probed, e),
+            }
+        });
+
+        tokio::spawn(remap_on_reset(state.clone(), gateway.clone()));
+    }
+    for group in state.groups.iter() {
+        let members = group
+            .members
+            .iter()
+            .map(|gateway| gateway.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        info!("Failover group {}: {}", group.name, members);
+        tokio::spawn(fail_over(state.clone(), group.clone()));
+    }
+    for (name, interface) in routed {
+        let gateway = state
+            .gateways
+            .iter()
+            .find(|gateway| gateway.name == name)
+            .expect("Routed gateway is configured");
+        tokio::spawn(route::follow(
+            gateway.clone(),
+            interface,
+            Duration::from_secs(args.route_interval),
+        ));
+    }
+    tokio::spawn(announce::listen(
+        state.gateways.to_vec(),
+        events.subscribe(),
+    ));
+    tokio::spawn(expire_mappings(state.clone()));
 
     // Setup graceful shutdown for multiple signals
-    let shutdown_signal = async {
+    let shutdown_signal = async move {
         #[cfg(unix)]
         {
             use tokio::signal::unix::{signal, SignalKind};
@@ -295,14 +2176,24 @@ async fn main() {
                 .expect("Failed to install signal handler");
             info!("Received shutdown signal, initiating graceful shutdown...");
         }
+
+        // Event streams never end on their own
+        events.close();
     };
 
     // Run server with graceful shutdown
-    let server = axum::serve(listener, app).with_graceful_shutdown(shutdown_signal);
-    
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal);
+
     if let Err(e) = server.await {
         error!("Server error: {}", e);
     } else {
+        if args.release_on_shutdown {
+            release_all(&state, Duration::from_secs(args.shutdown_timeout)).await;
+        }
         info!("Server shutdown complete");
     }
 }
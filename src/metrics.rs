@@ -0,0 +1,158 @@
+//! Prometheus metrics, collected in a process-wide registry and exported on /metrics
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::net::IpAddr;
+use std::sync::LazyLock;
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// `/forward` calls by protocol and outcome (`ok` or the error code)
+    pub forward_requests: IntCounterVec,
+    /// Time spent waiting for the gateway, by operation and outcome
+    pub gateway_duration: HistogramVec,
+    /// Requests sent again because the gateway did not answer in time
+    pub retransmissions: IntCounterVec,
+    pub mappings: IntGaugeVec,
+    pub managed_mappings: IntGaugeVec,
+    pub auth_failures: IntCounter,
+    pub gateway_epoch: IntGaugeVec,
+    /// Times a failover group moved its mappings to another gateway
+    pub failovers: IntCounterVec,
+    /// Always 1, labelled with each gateway's current public address
+    external_address: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let metrics = Metrics {
+            registry: Registry::new_custom(Some("natpmp_server".to_string()), None)
+                .expect("Invalid metrics prefix"),
+            forward_requests: IntCounterVec::new(
+                Opts::new(
+                    "forward_requests_total",
+                    "Port mapping requests to /forward",
+                ),
+                &["gateway", "protocol", "outcome"],
+            )
+            .unwrap(),
+            gateway_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "gateway_request_duration_seconds",
+                    "Round-trip time of requests to the gateway, including retransmissions",
+                )
+                .buckets(vec![
+                    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+                ]),
+                &["gateway", "operation", "outcome"],
+            )
+            .unwrap(),
+            retransmissions: IntCounterVec::new(
+                Opts::new(
+                    "gateway_retransmissions_total",
+                    "Requests resent to the gateway after a timeout",
+                ),
+                &["gateway", "protocol"],
+            )
+            .unwrap(),
+            mappings: IntGaugeVec::new(Opts::new("mappings", "Known mappings"), &["gateway"])
+                .unwrap(),
+            managed_mappings: IntGaugeVec::new(
+                Opts::new(
+                    "managed_mappings",
+                    "Mappings renewed by the server as managed leases",
+                ),
+                &["gateway"],
+            )
+            .unwrap(),
+            auth_failures: IntCounter::new(
+                "auth_failures_total",
+                "Requests rejected for a missing or wrong bearer token",
+            )
+            .unwrap(),
+            gateway_epoch: IntGaugeVec::new(
+                Opts::new(
+                    "gateway_epoch_seconds",
+                    "Seconds since the gateway's mapping table was initialized, as last reported",
+                ),
+                &["gateway"],
+            )
+            .unwrap(),
+            failovers: IntCounterVec::new(
+                Opts::new(
+                    "failovers_total",
+                    "Failover group switches to another gateway",
+                ),
+                &["group", "from", "to"],
+            )
+            .unwrap(),
+            external_address: IntGaugeVec::new(
+                Opts::new(
+                    "gateway_external_address_info",
+                    "Public address last reported by the gateway",
+                ),
+                &["gateway", "address"],
+            )
+            .unwrap(),
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 9] = [
+            Box::new(metrics.forward_requests.clone()),
+            Box::new(metrics.gateway_duration.clone()),
+            Box::new(metrics.retransmissions.clone()),
+            Box::new(metrics.mappings.clone()),
+            Box::new(metrics.managed_mappings.clone()),
+            Box::new(metrics.auth_failures.clone()),
+            Box::new(metrics.gateway_epoch.clone()),
+            Box::new(metrics.failovers.clone()),
+            Box::new(metrics.external_address.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("Duplicate metric");
+        }
+        metrics
+    }
+
+    /// Exports zeroes for a configured gateway up front, so it shows up before its
+    /// first mapping and rate() works from the first retransmission
+    pub fn add_gateway(&self, gateway: &str) {
+        for protocol in ["natpmp", "pcp"] {
+            self.retransmissions.with_label_values(&[gateway, protocol]);
+        }
+        self.mappings.with_label_values(&[gateway]);
+        self.managed_mappings.with_label_values(&[gateway]);
+    }
+
+    pub fn set_external_address(&self, gateway: &str, previous: Option<IpAddr>, address: IpAddr) {
+        if let Some(previous) = previous.filter(|&previous| previous != address) {
+            let _ = self
+                .external_address
+                .remove_label_values(&[gateway, &previous.to_string()]);
+        }
+        self.external_address
+            .with_label_values(&[gateway, &address.to_string()])
+            .set(1);
+    }
+
+    pub fn clear_external_address(&self, gateway: &str, address: IpAddr) {
+        let _ = self
+            .external_address
+            .remove_label_values(&[gateway, &address.to_string()]);
+    }
+
+    /// Renders every metric in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Failed to encode metrics");
+        String::from_utf8(buffer).expect("Metrics are not valid UTF-8")
+    }
+}
@@ -0,0 +1,377 @@
+//! Async NAT-PMP client (RFC 6886) on top of tokio's UDP socket
+
+use crate::gateway::Protocol;
+use crate::transport::{self, read_u16, read_u32, Schedule, Socket, SERVER_PORT};
+use prometheus::IntCounter;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+const VERSION: u8 = 0;
+const OP_PUBLIC_ADDRESS: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+const OP_MAP_TCP: u8 = 2;
+/// Responses carry the request opcode plus 128
+const OP_RESPONSE: u8 = 128;
+
+/// RFC 6886 section 3.1: start at 250 ms and double on every retry, up to 9 attempts
+const SCHEDULE: Schedule = Schedule {
+    protocol: "NAT-PMP",
+    initial_timeout: Duration::from_millis(250),
+    max_attempts: 9,
+};
+
+/// Non-zero result codes from RFC 6886 section 3.5
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResultCode {
+    UnsupportedVersion,
+    NotAuthorized,
+    NetworkFailure,
+    OutOfResources,
+    UnsupportedOpcode,
+    Other(u16),
+}
+
+impl From<u16> for ResultCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1 => ResultCode::UnsupportedVersion,
+            2 => ResultCode::NotAuthorized,
+            3 => ResultCode::NetworkFailure,
+            4 => ResultCode::OutOfResources,
+            5 => ResultCode::UnsupportedOpcode,
+            other => ResultCode::Other(other),
+        }
+    }
+}
+
+impl fmt::Display for ResultCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResultCode::UnsupportedVersion => write!(f, "unsupported version"),
+            ResultCode::NotAuthorized => write!(f, "not authorized/refused"),
+            ResultCode::NetworkFailure => write!(f, "network failure"),
+            ResultCode::OutOfResources => write!(f, "out of resources"),
+            ResultCode::UnsupportedOpcode => write!(f, "unsupported opcode"),
+            ResultCode::Other(code) => write!(f, "result code {}", code),
+        }
+    }
+}
+
+pub type Error = transport::Error<ResultCode>;
+
+pub struct PublicAddress {
+    /// Seconds since the gateway's port mapping table was initialized
+    pub epoch: u32,
+    pub address: Ipv4Addr,
+}
+
+pub struct MappingResponse {
+    pub epoch: u32,
+    pub external_port: u16,
+    pub lifetime: u32,
+}
+
+pub struct Client {
+    socket: Socket,
+}
+
+impl Client {
+    pub async fn new(gateway: Ipv4Addr, retransmissions: IntCounter) -> io::Result<Self> {
+        Self::connect(SocketAddr::from((gateway, SERVER_PORT)), retransmissions).await
+    }
+
+    async fn connect(gateway: SocketAddr, retransmissions: IntCounter) -> io::Result<Self> {
+        Ok(Client {
+            socket: Socket::connect(gateway, retransmissions).await?,
+        })
+    }
+
+    /// Asks the gateway for its public address (opcode 0)
+    pub async fn public_address(&self) -> Result<PublicAddress, Error> {
+        let response = self
+            .request(&[VERSION, OP_PUBLIC_ADDRESS], OP_PUBLIC_ADDRESS, |_| true)
+            .await?;
+
+        Ok(PublicAddress {
+            epoch: read_u32(&response, 4),
+            address: Ipv4Addr::new(response[8], response[9], response[10], response[11]),
+        })
+    }
+
+    /// Creates, renews or (with a zero lifetime) deletes a port mapping (opcodes 1 and 2)
+    pub async fn map(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    ) -> Result<MappingResponse, Error> {
+        let mut request = [0u8; 12];
+        request[0] = VERSION;
+        request[1] = map_opcode(protocol);
+        request[4..6].copy_from_slice(&internal_port.to_be_bytes());
+        request[6..8].copy_from_slice(&external_port.to_be_bytes());
+        request[8..12].copy_from_slice(&lifetime.to_be_bytes());
+
+        let response = self
+            .request(&request, map_opcode(protocol), |response| {
+                read_u16(response, 8) == internal_port
+            })
+            .await?;
+
+        Ok(MappingResponse {
+            epoch: read_u32(&response, 4),
+            external_port: read_u16(&response, 10),
+            lifetime: read_u32(&response, 12),
+        })
+    }
+
+    /// Sends a request with the RFC retransmission schedule and returns the first
+    /// response that matches its opcode (and `matches`, for successful responses)
+    async fn request(
+        &self,
+        request: &[u8],
+        opcode: u8,
+        matches: impl Fn(&[u8]) -> bool,
+    ) -> Result<Vec<u8>, Error> {
+        let expected_len = response_len(opcode);
+        self.socket
+            .request(request, &SCHEDULE, |response| {
+                // Result code 1 may come back with a different version, e.g. from PCP servers
+                if response.len() >= 4 && read_u16(response, 2) == 1 {
+                    return Some(Err(ResultCode::UnsupportedVersion));
+                }
+                if response.len() < 4
+                    || response[0] != VERSION
+                    || response[1] != OP_RESPONSE + opcode
+                {
+                    return None;
+                }
+                let result = read_u16(response, 2);
+                if result != 0 {
+                    return Some(Err(ResultCode::from(result)));
+                }
+                if response.len() < expected_len || !matches(response) {
+                    return None;
+                }
+                Some(Ok(response[..expected_len].to_vec()))
+            })
+            .await
+    }
+}
+
+/// Parses an unsolicited public address response, which the gateway multicasts
+/// when its address changes or it restarts (RFC 6886 section 3.2.1)
+pub fn parse_announcement(packet: &[u8]) -> Option<PublicAddress> {
+    let len = response_len(OP_PUBLIC_ADDRESS);
+    if packet.len() < len
+        || packet[0] != VERSION
+        || packet[1] != OP_RESPONSE + OP_PUBLIC_ADDRESS
+        || read_u16(packet, 2) != 0
+    {
+        return None;
+    }
+    Some(PublicAddress {
+        epoch: read_u32(packet, 4),
+        address: Ipv4Addr::new(packet[8], packet[9], packet[10], packet[11]),
+    })
+}
+
+fn map_opcode(protocol: Protocol) -> u8 {
+    match protocol {
+        Protocol::Udp => OP_MAP_UDP,
+        Protocol::Tcp => OP_MAP_TCP,
+    }
+}
+
+fn response_len(opcode: u8) -> usize {
+    match opcode {
+        OP_PUBLIC_ADDRESS => 12,
+        _ => 16,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::tests::{counter, receive, stand_in};
+    use tokio::net::UdpSocket;
+
+    async fn gateway() -> (UdpSocket, Client) {
+        counting_gateway(counter()).await
+    }
+
+    async fn counting_gateway(retransmissions: IntCounter) -> (UdpSocket, Client) {
+        let gateway = stand_in().await;
+        let client = Client::connect(gateway.local_addr().unwrap(), retransmissions)
+            .await
+            .unwrap();
+        (gateway, client)
+    }
+
+    fn public_address_response(result: u16, epoch: u32, address: [u8; 4]) -> Vec<u8> {
+        let mut response = vec![VERSION, OP_RESPONSE + OP_PUBLIC_ADDRESS];
+        response.extend_from_slice(&result.to_be_bytes());
+        response.extend_from_slice(&epoch.to_be_bytes());
+        response.extend_from_slice(&address);
+        response
+    }
+
+    fn map_response(opcode: u8, internal_port: u16, external_port: u16, lifetime: u32) -> Vec<u8> {
+        let mut response = vec![VERSION, OP_RESPONSE + opcode, 0, 0];
+        response.extend_from_slice(&1234u32.to_be_bytes());
+        response.extend_from_slice(&internal_port.to_be_bytes());
+        response.extend_from_slice(&external_port.to_be_bytes());
+        response.extend_from_slice(&lifetime.to_be_bytes());
+        response
+    }
+
+    #[tokio::test]
+    async fn public_address_round_trip() {
+        let (gateway, client) = gateway().await;
+        let request = tokio::spawn(async move { client.public_address().await });
+
+        let (packet, from) = receive(&gateway).await;
+        assert_eq!(packet, [0, 0]);
+        let response = public_address_response(0, 86400, [203, 0, 113, 7]);
+        gateway.send_to(&response, from).await.unwrap();
+
+        let address = request.await.unwrap().unwrap();
+        assert_eq!(address.epoch, 86400);
+        assert_eq!(address.address, Ipv4Addr::new(203, 0, 113, 7));
+    }
+
+    #[tokio::test]
+    async fn map_request_packets() {
+        for (protocol, opcode) in [(Protocol::Udp, OP_MAP_UDP), (Protocol::Tcp, OP_MAP_TCP)] {
+            let (gateway, client) = gateway().await;
+            let request = tokio::spawn(async move { client.map(protocol, 6881, 40000, 60).await });
+
+            let (packet, from) = receive(&gateway).await;
+            assert_eq!(
+                packet,
+                [0, opcode, 0, 0, 0x1a, 0xe1, 0x9c, 0x40, 0, 0, 0, 60]
+            );
+            let response = map_response(opcode, 6881, 40001, 59);
+            gateway.send_to(&response, from).await.unwrap();
+
+            let mapping = request.await.unwrap().unwrap();
+            assert_eq!(mapping.epoch, 1234);
+            assert_eq!(mapping.external_port, 40001);
+            assert_eq!(mapping.lifetime, 59);
+        }
+    }
+
+    #[tokio::test]
+    async fn map_ignores_responses_for_other_requests() {
+        let (gateway, client) = gateway().await;
+        let request = tokio::spawn(async move { client.map(Protocol::Tcp, 6881, 0, 60).await });
+
+        let (_, from) = receive(&gateway).await;
+        // Another internal port, the other protocol, a truncated packet, then the answer
+        for response in [
+            map_response(OP_MAP_TCP, 6882, 50000, 60),
+            map_response(OP_MAP_UDP, 6881, 50001, 60),
+            map_response(OP_MAP_TCP, 6881, 50002, 60)[..10].to_vec(),
+            map_response(OP_MAP_TCP, 6881, 40000, 60),
+        ] {
+            gateway.send_to(&response, from).await.unwrap();
+        }
+
+        assert_eq!(request.await.unwrap().unwrap().external_port, 40000);
+    }
+
+    #[tokio::test]
+    async fn result_codes() {
+        assert_eq!(ResultCode::from(1), ResultCode::UnsupportedVersion);
+        assert_eq!(ResultCode::from(2), ResultCode::NotAuthorized);
+        assert_eq!(ResultCode::from(3), ResultCode::NetworkFailure);
+        assert_eq!(ResultCode::from(4), ResultCode::OutOfResources);
+        assert_eq!(ResultCode::from(5), ResultCode::UnsupportedOpcode);
+        assert_eq!(ResultCode::from(42), ResultCode::Other(42));
+
+        let (gateway, client) = gateway().await;
+        let request = tokio::spawn(async move { client.map(Protocol::Udp, 6881, 0, 60).await });
+        let (_, from) = receive(&gateway).await;
+        let mut response = map_response(OP_MAP_UDP, 6881, 0, 0);
+        response[2..4].copy_from_slice(&4u16.to_be_bytes());
+        gateway.send_to(&response, from).await.unwrap();
+
+        assert!(matches!(
+            request.await.unwrap(),
+            Err(Error::Gateway(ResultCode::OutOfResources))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unsupported_version_from_pcp_gateway() {
+        let (gateway, client) = gateway().await;
+        let request = tokio::spawn(async move { client.public_address().await });
+        let (_, from) = receive(&gateway).await;
+        // A PCP server answers with its own version and the result code in byte 3
+        let mut response = vec![2, OP_RESPONSE, 0, 1];
+        response.extend_from_slice(&[0; 20]);
+        gateway.send_to(&response, from).await.unwrap();
+
+        assert!(matches!(
+            request.await.unwrap(),
+            Err(Error::Gateway(ResultCode::UnsupportedVersion))
+        ));
+    }
+
+    #[tokio::test]
+    async fn retransmits_until_answered() {
+        let retransmissions = counter();
+        let (gateway, client) = counting_gateway(retransmissions.clone()).await;
+        let request = tokio::spawn(async move { client.public_address().await });
+
+        // Drop the first request; the answer to the retransmission counts
+        let (first, _) = receive(&gateway).await;
+        let (second, from) = receive(&gateway).await;
+        assert_eq!(first, second);
+        let response = public_address_response(0, 1, [203, 0, 113, 7]);
+        gateway.send_to(&response, from).await.unwrap();
+
+        assert!(request.await.unwrap().is_ok());
+        assert_eq!(retransmissions.get(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_nine_attempts() {
+        let retransmissions = counter();
+        let (gateway, client) = counting_gateway(retransmissions.clone()).await;
+        let started = tokio::time::Instant::now();
+
+        let result = client.public_address().await;
+
+        assert!(matches!(result, Err(Error::Timeout)));
+        // 250 ms doubling over 9 attempts: 250 * (2^9 - 1) ms
+        assert_eq!(started.elapsed(), Duration::from_millis(127_750));
+        assert_eq!(retransmissions.get(), 8);
+        let mut buf = [0u8; 16];
+        let mut sent = 0;
+        while gateway.try_recv_from(&mut buf).is_ok() {
+            sent += 1;
+        }
+        assert_eq!(sent, SCHEDULE.max_attempts);
+    }
+
+    #[test]
+    fn announcements() {
+        let packet = public_address_response(0, 7, [198, 51, 100, 4]);
+        let announcement = parse_announcement(&packet).unwrap();
+        assert_eq!(announcement.epoch, 7);
+        assert_eq!(announcement.address, Ipv4Addr::new(198, 51, 100, 4));
+
+        assert!(parse_announcement(&packet[..11]).is_none());
+        assert!(parse_announcement(&public_address_response(3, 7, [0; 4])).is_none());
+        let mut mapping = packet.clone();
+        mapping[1] = OP_RESPONSE + OP_MAP_UDP;
+        assert!(parse_announcement(&mapping).is_none());
+        let mut pcp = packet;
+        pcp[0] = 2;
+        assert!(parse_announcement(&pcp).is_none());
+    }
+}
@@ -0,0 +1,510 @@
+//! Async Port Control Protocol client (RFC 6887) for the ANNOUNCE, MAP and PEER opcodes
+
+use crate::gateway::Protocol;
+use crate::transport::{self, read_u16, read_u32, Schedule, Socket, SERVER_PORT};
+use prometheus::IntCounter;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+const VERSION: u8 = 2;
+const OP_ANNOUNCE: u8 = 0;
+const OP_MAP: u8 = 1;
+const OP_PEER: u8 = 2;
+/// The R bit marks responses
+const OP_RESPONSE: u8 = 0x80;
+
+const HEADER_LEN: usize = 24;
+const MAP_LEN: usize = 36;
+const PEER_LEN: usize = 56;
+
+/// RFC 6887 section 13.1: maps on behalf of another host
+const OPTION_THIRD_PARTY: u8 = 1;
+const THIRD_PARTY_LEN: usize = 20;
+
+/// RFC 6887 section 8.1.1 starts at 3 s and doubles without a retry limit; the
+/// number of attempts is capped so HTTP requests finish in bounded time
+const SCHEDULE: Schedule = Schedule {
+    protocol: "PCP",
+    initial_timeout: Duration::from_secs(3),
+    max_attempts: 4,
+};
+
+/// Non-zero result codes from RFC 6887 section 7.4
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResultCode {
+    UnsupportedVersion,
+    NotAuthorized,
+    MalformedRequest,
+    UnsupportedOpcode,
+    UnsupportedOption,
+    MalformedOption,
+    NetworkFailure,
+    NoResources,
+    UnsupportedProtocol,
+    UserExceededQuota,
+    CannotProvideExternal,
+    AddressMismatch,
+    ExcessiveRemotePeers,
+    Other(u8),
+}
+
+impl From<u8> for ResultCode {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => ResultCode::UnsupportedVersion,
+            2 => ResultCode::NotAuthorized,
+            3 => ResultCode::MalformedRequest,
+            4 => ResultCode::UnsupportedOpcode,
+            5 => ResultCode::UnsupportedOption,
+            6 => ResultCode::MalformedOption,
+            7 => ResultCode::NetworkFailure,
+            8 => ResultCode::NoResources,
+            9 => ResultCode::UnsupportedProtocol,
+            10 => ResultCode::UserExceededQuota,
+            11 => ResultCode::CannotProvideExternal,
+            12 => ResultCode::AddressMismatch,
+            13 => ResultCode::ExcessiveRemotePeers,
+            other => ResultCode::Other(other),
+        }
+    }
+}
+
+impl fmt::Display for ResultCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResultCode::UnsupportedVersion => write!(f, "UNSUPP_VERSION"),
+            ResultCode::NotAuthorized => write!(f, "NOT_AUTHORIZED"),
+            ResultCode::MalformedRequest => write!(f, "MALFORMED_REQUEST"),
+            ResultCode::UnsupportedOpcode => write!(f, "UNSUPP_OPCODE"),
+            ResultCode::UnsupportedOption => write!(f, "UNSUPP_OPTION"),
+            ResultCode::MalformedOption => write!(f, "MALFORMED_OPTION"),
+            ResultCode::NetworkFailure => write!(f, "NETWORK_FAILURE"),
+            ResultCode::NoResources => write!(f, "NO_RESOURCES"),
+            ResultCode::UnsupportedProtocol => write!(f, "UNSUPP_PROTOCOL"),
+            ResultCode::UserExceededQuota => write!(f, "USER_EX_QUOTA"),
+            ResultCode::CannotProvideExternal => write!(f, "CANNOT_PROVIDE_EXTERNAL"),
+            ResultCode::AddressMismatch => write!(f, "ADDRESS_MISMATCH"),
+            ResultCode::ExcessiveRemotePeers => write!(f, "EXCESSIVE_REMOTE_PEERS"),
+            ResultCode::Other(code) => write!(f, "result code {}", code),
+        }
+    }
+}
+
+pub type Error = transport::Error<ResultCode>;
+
+pub struct MapRequest<'a> {
+    pub nonce: &'a [u8; 12],
+    pub protocol: Protocol,
+    pub internal_port: u16,
+    pub external_port: u16,
+    pub lifetime: u32,
+    /// Remote peer for a PEER request
+    pub peer: Option<SocketAddr>,
+    /// Internal host to map for instead of the sender (THIRD_PARTY option)
+    pub third_party: Option<IpAddr>,
+}
+
+pub struct MappingResponse {
+    pub epoch: u32,
+    pub lifetime: u32,
+    pub external_port: u16,
+    pub external_address: IpAddr,
+}
+
+pub struct Client {
+    socket: Socket,
+    /// Source address of our requests, which the gateway checks against the header
+    client_address: IpAddr,
+}
+
+impl Client {
+    pub async fn new(gateway: IpAddr, retransmissions: IntCounter) -> io::Result<Self> {
+        Self::connect(SocketAddr::new(gateway, SERVER_PORT), retransmissions).await
+    }
+
+    async fn connect(gateway: SocketAddr, retransmissions: IntCounter) -> io::Result<Self> {
+        let socket = Socket::connect(gateway, retransmissions).await?;
+        let client_address = socket.local_address()?;
+        Ok(Client {
+            socket,
+            client_address,
+        })
+    }
+
+    /// Sends an ANNOUNCE request, which only reports the gateway's epoch
+    pub async fn announce(&self) -> Result<u32, Error> {
+        let request = self.header(OP_ANNOUNCE, 0);
+        let response = self.request(&request, OP_ANNOUNCE, |_| true).await?;
+        Ok(read_u32(&response, 8))
+    }
+
+    /// Creates, renews or (with a zero lifetime) deletes a mapping, using the PEER
+    /// opcode when a remote peer is given and MAP otherwise
+    pub async fn map(&self, request: &MapRequest<'_>) -> Result<MappingResponse, Error> {
+        let opcode = match request.peer {
+            Some(_) => OP_PEER,
+            None => OP_MAP,
+        };
+        let internal_address = request.third_party.unwrap_or(self.client_address);
+        // Without a preference the suggested external address is the all-zeros
+        // address of the family we are mapping
+        let any_address = match internal_address {
+            IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.to_ipv6_mapped(),
+            IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED,
+        };
+
+        let mut packet = self.header(opcode, request.lifetime);
+        packet.extend_from_slice(request.nonce);
+        packet.extend_from_slice(&[protocol_number(request.protocol), 0, 0, 0]);
+        packet.extend_from_slice(&request.internal_port.to_be_bytes());
+        packet.extend_from_slice(&request.external_port.to_be_bytes());
+        packet.extend_from_slice(&any_address.octets());
+        if let Some(peer) = request.peer {
+            packet.extend_from_slice(&peer.port().to_be_bytes());
+            packet.extend_from_slice(&[0, 0]);
+            packet.extend_from_slice(&to_ipv6(peer.ip()).octets());
+        }
+        if let Some(third_party) = request.third_party {
+            packet.extend_from_slice(&[OPTION_THIRD_PARTY, 0]);
+            packet.extend_from_slice(&16u16.to_be_bytes());
+            packet.extend_from_slice(&to_ipv6(third_party).octets());
+        }
+
+        let payload_len = match request.peer {
+            Some(_) => PEER_LEN,
+            None => MAP_LEN,
+        };
+        let response = self
+            .request(&packet, opcode, |response| {
+                response.len() >= HEADER_LEN + payload_len && matches_mapping(response, request)
+            })
+            .await?;
+        Ok(parse_mapping(&response))
+    }
+
+    fn header(&self, opcode: u8, lifetime: u32) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LEN + PEER_LEN + THIRD_PARTY_LEN);
+        header.extend_from_slice(&[VERSION, opcode, 0, 0]);
+        header.extend_from_slice(&lifetime.to_be_bytes());
+        header.extend_from_slice(&to_ipv6(self.client_address).octets());
+        header
+    }
+
+    /// Sends a request with exponential backoff and returns the first response that
+    /// matches its opcode and `matches`
+    async fn request(
+        &self,
+        request: &[u8],
+        opcode: u8,
+        matches: impl Fn(&[u8]) -> bool,
+    ) -> Result<Vec<u8>, Error> {
+        self.socket
+            .request(request, &SCHEDULE, |response| {
+                // Gateways that speak another version answer with the version they
+                // support; NAT-PMP ones use their own format with a 16-bit result code
+                if response.len() >= 4 && response[0] != VERSION {
+                    let result = match response[0] {
+                        0 => read_u16(response, 2),
+                        _ => response[3].into(),
+                    };
+                    if result == 1 {
+                        return Some(Err(ResultCode::UnsupportedVersion));
+                    }
+                }
+                if response.len() < HEADER_LEN
+                    || response[0] != VERSION
+                    || response[1] != OP_RESPONSE | opcode
+                    || !matches(response)
+                {
+                    return None;
+                }
+                match response[3] {
+                    0 => Some(Ok(response.to_vec())),
+                    code => Some(Err(ResultCode::from(code))),
+                }
+            })
+            .await
+    }
+}
+
+/// Parses an unsolicited ANNOUNCE response, which the gateway multicasts after a
+/// restart (RFC 6887 section 14.1.1), and returns its epoch
+pub fn parse_announcement(packet: &[u8]) -> Option<u32> {
+    if packet.len() < HEADER_LEN
+        || packet[0] != VERSION
+        || packet[1] != OP_RESPONSE | OP_ANNOUNCE
+        || packet[3] != 0
+    {
+        return None;
+    }
+    Some(read_u32(packet, 8))
+}
+
+fn matches_mapping(response: &[u8], request: &MapRequest<'_>) -> bool {
+    let payload = &response[HEADER_LEN..];
+    payload[..12] == request.nonce[..]
+        && payload[12] == protocol_number(request.protocol)
+        && read_u16(payload, 16) == request.internal_port
+        && match request.peer {
+            Some(peer) => read_u16(payload, 36) == peer.port(),
+            None => true,
+        }
+}
+
+fn parse_mapping(response: &[u8]) -> MappingResponse {
+    let payload = &response[HEADER_LEN..];
+    let mut address = [0u8; 16];
+    address.copy_from_slice(&payload[20..36]);
+
+    MappingResponse {
+        lifetime: read_u32(response, 4),
+        epoch: read_u32(response, 8),
+        external_port: read_u16(payload, 18),
+        external_address: from_ipv6(Ipv6Addr::from(address)),
+    }
+}
+
+/// IANA protocol numbers used in the MAP and PEER opcodes
+fn protocol_number(protocol: Protocol) -> u8 {
+    match protocol {
+        Protocol::Tcp => 6,
+        Protocol::Udp => 17,
+    }
+}
+
+/// PCP carries every address as IPv6, with IPv4 in its IPv4-mapped form
+fn to_ipv6(address: IpAddr) -> Ipv6Addr {
+    match address {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+fn from_ipv6(address: Ipv6Addr) -> IpAddr {
+    match address.to_ipv4_mapped() {
+        Some(v4) => IpAddr::V4(v4),
+        None => IpAddr::V6(address),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::tests::{counter, receive, stand_in};
+    use tokio::net::UdpSocket;
+
+    const NONCE: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+    async fn gateway() -> (UdpSocket, Client) {
+        let gateway = stand_in().await;
+        let client = Client::connect(gateway.local_addr().unwrap(), counter())
+            .await
+            .unwrap();
+        (gateway, client)
+    }
+
+    fn map_request(peer: Option<SocketAddr>) -> MapRequest<'static> {
+        MapRequest {
+            nonce: &NONCE,
+            protocol: Protocol::Tcp,
+            internal_port: 6881,
+            external_port: 40000,
+            lifetime: 60,
+            peer,
+            third_party: None,
+        }
+    }
+
+    /// The response a gateway sends for `request`, granting `external_port`
+    fn response(request: &[u8], result: u8, external_port: u16) -> Vec<u8> {
+        let mut response = vec![VERSION, OP_RESPONSE | request[1], 0, result];
+        response.extend_from_slice(&request[4..8]);
+        response.extend_from_slice(&5000u32.to_be_bytes());
+        response.extend_from_slice(&[0; 12]);
+        let mut payload = request[HEADER_LEN..].to_vec();
+        if payload.len() >= MAP_LEN {
+            payload[18..20].copy_from_slice(&external_port.to_be_bytes());
+            payload[20..36]
+                .copy_from_slice(&Ipv4Addr::new(198, 51, 100, 9).to_ipv6_mapped().octets());
+        }
+        response.extend_from_slice(&payload);
+        response
+    }
+
+    fn client_address() -> [u8; 16] {
+        Ipv4Addr::LOCALHOST.to_ipv6_mapped().octets()
+    }
+
+    #[tokio::test]
+    async fn announce_round_trip() {
+        let (gateway, client) = gateway().await;
+        let request = tokio::spawn(async move { client.announce().await });
+
+        let (packet, from) = receive(&gateway).await;
+        let mut expected = vec![2, 0, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(&client_address());
+        assert_eq!(packet, expected);
+        gateway
+            .send_to(&response(&packet, 0, 0), from)
+            .await
+            .unwrap();
+
+        assert_eq!(request.await.unwrap().unwrap(), 5000);
+    }
+
+    #[tokio::test]
+    async fn map_round_trip() {
+        let (gateway, client) = gateway().await;
+        let request = tokio::spawn(async move { client.map(&map_request(None)).await });
+
+        let (packet, from) = receive(&gateway).await;
+        let mut expected = vec![2, OP_MAP, 0, 0, 0, 0, 0, 60];
+        expected.extend_from_slice(&client_address());
+        expected.extend_from_slice(&NONCE);
+        expected.extend_from_slice(&[6, 0, 0, 0, 0x1a, 0xe1, 0x9c, 0x40]);
+        expected.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+        assert_eq!(packet, expected);
+        gateway
+            .send_to(&response(&packet, 0, 40001), from)
+            .await
+            .unwrap();
+
+        let mapping = request.await.unwrap().unwrap();
+        assert_eq!(mapping.lifetime, 60);
+        assert_eq!(mapping.epoch, 5000);
+        assert_eq!(mapping.external_port, 40001);
+        assert_eq!(
+            mapping.external_address,
+            IpAddr::V4(Ipv4Addr::new(198, 51, 100, 9))
+        );
+    }
+
+    #[tokio::test]
+    async fn third_party_option() {
+        let (gateway, client) = gateway().await;
+        let host: IpAddr = "2001:db8::7".parse().unwrap();
+        let request = tokio::spawn(async move {
+            let request = MapRequest {
+                third_party: Some(host),
+                ..map_request(None)
+            };
+            client.map(&request).await
+        });
+
+        let (packet, from) = receive(&gateway).await;
+        assert_eq!(packet.len(), HEADER_LEN + MAP_LEN + THIRD_PARTY_LEN);
+        // An IPv6 host gets an IPv6 all-zeros suggestion
+        assert_eq!(packet[HEADER_LEN + 20..HEADER_LEN + 36], [0; 16]);
+        let option = &packet[HEADER_LEN + MAP_LEN..];
+        assert_eq!(option[..4], [OPTION_THIRD_PARTY, 0, 0, 16]);
+        assert_eq!(option[4..], to_ipv6(host).octets());
+        gateway
+            .send_to(&response(&packet, 0, 40000), from)
+            .await
+            .unwrap();
+
+        assert!(request.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn peer_round_trip() {
+        let (gateway, client) = gateway().await;
+        let peer: SocketAddr = "192.0.2.5:443".parse().unwrap();
+        let request = tokio::spawn(async move { client.map(&map_request(Some(peer))).await });
+
+        let (packet, from) = receive(&gateway).await;
+        assert_eq!(packet[1], OP_PEER);
+        assert_eq!(packet.len(), HEADER_LEN + PEER_LEN);
+        let payload = &packet[HEADER_LEN..];
+        assert_eq!(payload[36..38], 443u16.to_be_bytes());
+        assert_eq!(payload[38..40], [0, 0]);
+        assert_eq!(
+            payload[40..56],
+            Ipv4Addr::new(192, 0, 2, 5).to_ipv6_mapped().octets()
+        );
+
+        // A response for another peer port or nonce belongs to another request
+        let mut other_peer = response(&packet, 0, 50000);
+        other_peer[HEADER_LEN + 36..HEADER_LEN + 38].copy_from_slice(&80u16.to_be_bytes());
+        let mut other_nonce = response(&packet, 0, 50001);
+        other_nonce[HEADER_LEN] ^= 0xff;
+        for response in [other_peer, other_nonce, response(&packet, 0, 40002)] {
+            gateway.send_to(&response, from).await.unwrap();
+        }
+
+        assert_eq!(request.await.unwrap().unwrap().external_port, 40002);
+    }
+
+    #[tokio::test]
+    async fn ignores_truncated_responses() {
+        let (gateway, client) = gateway().await;
+        let request = tokio::spawn(async move { client.map(&map_request(None)).await });
+
+        let (packet, from) = receive(&gateway).await;
+        let full = response(&packet, 0, 40003);
+        for response in [
+            &full[..3],
+            &full[..HEADER_LEN - 1],
+            &full[..HEADER_LEN + 20],
+            &full,
+        ] {
+            gateway.send_to(response, from).await.unwrap();
+        }
+
+        assert_eq!(request.await.unwrap().unwrap().external_port, 40003);
+    }
+
+    #[tokio::test]
+    async fn result_code() {
+        let (gateway, client) = gateway().await;
+        let request = tokio::spawn(async move { client.map(&map_request(None)).await });
+
+        let (packet, from) = receive(&gateway).await;
+        gateway
+            .send_to(&response(&packet, 8, 0), from)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            request.await.unwrap(),
+            Err(Error::Gateway(ResultCode::NoResources))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unsupported_version_from_natpmp_gateway() {
+        let (gateway, client) = gateway().await;
+        let request = tokio::spawn(async move { client.announce().await });
+
+        let (_, from) = receive(&gateway).await;
+        // NAT-PMP answers with version 0 and a 16-bit result code
+        let response = [0, OP_RESPONSE, 0, 1, 0, 0, 0, 9];
+        gateway.send_to(&response, from).await.unwrap();
+
+        assert!(matches!(
+            request.await.unwrap(),
+            Err(Error::Gateway(ResultCode::UnsupportedVersion))
+        ));
+    }
+
+    #[test]
+    fn announcements() {
+        let mut packet = vec![VERSION, OP_RESPONSE | OP_ANNOUNCE, 0, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(&42u32.to_be_bytes());
+        packet.extend_from_slice(&[0; 12]);
+        assert_eq!(parse_announcement(&packet), Some(42));
+
+        assert_eq!(parse_announcement(&packet[..HEADER_LEN - 1]), None);
+        let mut failed = packet.clone();
+        failed[3] = 7;
+        assert_eq!(parse_announcement(&failed), None);
+        let mut map = packet.clone();
+        map[1] = OP_RESPONSE | OP_MAP;
+        assert_eq!(parse_announcement(&map), None);
+        let mut request = packet;
+        request[1] = OP_ANNOUNCE;
+        assert_eq!(parse_announcement(&request), None);
+    }
+}
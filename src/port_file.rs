@@ -0,0 +1,171 @@
+//! Files holding a mapping's external port, for apps that read the forwarded port
+//! from a file
+//!
+//! API clients name the files, so they are kept inside the operator's
+//! `--port-file-dir`.
+
+use crate::events::MappingDetails;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::IpAddr;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Just the port number
+    #[default]
+    Port,
+    Json,
+}
+
+#[derive(Serialize)]
+struct Contents<'a> {
+    external_address: Option<IpAddr>,
+    external_port: u16,
+    protocol: &'a str,
+    internal_port: u16,
+    gateway: &'a str,
+    expires_at: DateTime<Utc>,
+}
+
+pub struct PortFiles {
+    /// Canonical path of --port-file-dir
+    dir: PathBuf,
+}
+
+impl PortFiles {
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        Ok(PortFiles {
+            dir: dir.canonicalize()?,
+        })
+    }
+
+    /// Checks that a mapping's `port_file` stays inside the directory
+    pub fn check(&self, name: &str) -> Result<(), String> {
+        self.path(name).map(|_| ())
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, String> {
+        let relative = Path::new(name);
+        let inside = relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if name.is_empty() || !inside {
+            return Err(format!(
+                "port_file must be a relative path inside {} without '..'",
+                self.dir.display()
+            ));
+        }
+        // Symlinks inside the directory must not lead out of it
+        let path = self.dir.join(relative);
+        let existing = path
+            .ancestors()
+            .skip(1)
+            .find(|ancestor| ancestor.exists())
+            .unwrap_or(&self.dir);
+        let contained = existing
+            .canonicalize()
+            .is_ok_and(|existing| existing.starts_with(&self.dir));
+        if !contained {
+            return Err(format!("port_file must be in {}", self.dir.display()));
+        }
+        Ok(path)
+    }
+
+    /// Writes the mapping's port through a temporary file, so readers never see a
+    /// half-written one. Files that already hold the same contents are left alone,
+    /// so watchers of plain port files only wake up when the port changes; JSON files
+    /// also change on every renewal, with the new expiry.
+    pub fn write(
+        &self,
+        name: &str,
+        format: Format,
+        mapping: &MappingDetails,
+        external_address: Option<IpAddr>,
+    ) -> io::Result<()> {
+        let path = self.path(name).map_err(io::Error::other)?;
+        let contents = match format {
+            Format::Port => format!("{}\n", mapping.external_port),
+            Format::Json => {
+                let contents = Contents {
+                    external_address,
+                    external_port: mapping.external_port,
+                    protocol: &mapping.protocol,
+                    internal_port: mapping.internal_port,
+                    gateway: &mapping.gateway,
+                    expires_at: mapping.expires_at,
+                };
+                serde_json::to_string_pretty(&contents)? + "\n"
+            }
+        };
+        if std::fs::read_to_string(&path).is_ok_and(|current| current == contents) {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+        // Writing follows symlinks, so never write through one left in the way
+        remove(Path::new(&temporary))?;
+        std::fs::write(&temporary, contents)?;
+        std::fs::rename(&temporary, &path)
+    }
+
+    pub fn remove(&self, name: &str) -> io::Result<()> {
+        remove(&self.path(name).map_err(io::Error::other)?)
+    }
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// A fresh port file directory under the system's temporary directory
+    fn port_file_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("natpmp-port-files-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn names_stay_inside_the_directory() {
+        let dir = port_file_dir("check");
+        let port_files = PortFiles::new(&dir).unwrap();
+
+        assert!(port_files.check("forwarded_port").is_ok());
+        assert!(port_files.check("qbittorrent/port").is_ok());
+        for name in ["", "..", "../port", "a/../../port", "/etc/port", "./port"] {
+            assert!(port_files.check(name).is_err(), "{:?} was accepted", name);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn symlinks_stay_inside_the_directory() {
+        let dir = port_file_dir("symlink");
+        let outside = port_file_dir("outside");
+        symlink(&outside, dir.join("out")).unwrap();
+        symlink(&dir, dir.join("self")).unwrap();
+        let port_files = PortFiles::new(&dir).unwrap();
+
+        assert!(port_files.check("out/port").is_err());
+        assert!(port_files.check("out/new/port").is_err());
+        assert!(port_files.check("self/port").is_ok());
+        assert!(port_files.remove("out/port").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+//! qBittorrent WebUI client that sets the listening port (Web API v2)
+
+use crate::integration::PortTarget;
+use reqwest::{header, StatusCode, Url};
+
+pub struct Qbittorrent {
+    url: Url,
+    /// None when the WebUI lets this host in without logging in
+    credentials: Option<(String, String)>,
+    client: reqwest::Client,
+    /// Session cookie from the last login
+    session: Option<String>,
+}
+
+impl Qbittorrent {
+    pub fn new(url: Url, credentials: Option<(String, String)>, client: reqwest::Client) -> Self {
+        // Relative API paths would replace the last segment of a path like /qbittorrent
+        let mut url = url;
+        if !url.path().ends_with('/') {
+            let path = format!("{}/", url.path());
+            url.set_path(&path);
+        }
+        Qbittorrent {
+            url,
+            credentials,
+            client,
+            session: None,
+        }
+    }
+
+    fn endpoint(&self, path: &str) -> Result<Url, String> {
+        self.url
+            .join(path)
+            .map_err(|e| format!("invalid URL: {}", e))
+    }
+
+    async fn login(&mut self) -> Result<(), String> {
+        let Some((username, password)) = &self.credentials else {
+            return Ok(());
+        };
+        let response = self
+            .client
+            .post(self.endpoint("api/v2/auth/login")?)
+            .form(&[("username", username), ("password", password)])
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if response.status() == StatusCode::FORBIDDEN {
+            return Err("login refused, too many failed attempts".to_string());
+        }
+        let response = response.error_for_status().map_err(|e| e.to_string())?;
+        let session = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .filter_map(|cookie| cookie.to_str().ok())
+            .filter_map(|cookie| cookie.split(';').next())
+            .find(|cookie| cookie.starts_with("SID="))
+            .map(str::to_string);
+        // Bad credentials still get 200, with "Fails." instead of "Ok."
+        match session {
+            Some(session) => {
+                self.session = Some(session);
+                Ok(())
+            }
+            None => Err("login failed, check the username and password".to_string()),
+        }
+    }
+
+    async fn set_preferences(&self, port: u16) -> Result<StatusCode, String> {
+        let preferences = serde_json::json!({ "listen_port": port }).to_string();
+        let mut request = self
+            .client
+            .post(self.endpoint("api/v2/app/setPreferences")?)
+            .form(&[("json", preferences)]);
+        if let Some(session) = &self.session {
+            request = request.header(header::COOKIE, session);
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        Ok(response.status())
+    }
+}
+
+impl PortTarget for Qbittorrent {
+    fn name(&self) -> &'static str {
+        "qBittorrent"
+    }
+
+    async fn set_port(&mut self, port: u16) -> Result<(), String> {
+        if self.session.is_none() {
+            self.login().await?;
+        }
+        let mut status = self.set_preferences(port).await?;
+        // The session expired, or qBittorrent restarted
+        if status == StatusCode::FORBIDDEN && self.credentials.is_some() {
+            self.session = None;
+            self.login().await?;
+            status = self.set_preferences(port).await?;
+        }
+        match status {
+            StatusCode::OK => Ok(()),
+            StatusCode::FORBIDDEN => Err("not logged in".to_string()),
+            status => Err(format!("setPreferences answered {}", status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use axum::http::HeaderMap;
+    use axum::response::IntoResponse;
+    use axum::routing::post;
+    use axum::Form;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    #[derive(Default)]
+    struct WebUi {
+        logins: u32,
+        /// Session cookie qBittorrent currently accepts
+        session: Option<String>,
+        ports: Vec<u16>,
+    }
+
+    type Shared = Arc<Mutex<WebUi>>;
+
+    async fn login(
+        State(webui): State<Shared>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> impl IntoResponse {
+        let mut webui = webui.lock().unwrap();
+        if form["username"] != "admin" || form["password"] != "adminadmin" {
+            return (HeaderMap::new(), "Fails.");
+        }
+        webui.logins += 1;
+        let session = format!("SID=session{}", webui.logins);
+        let mut headers = HeaderMap::new();
+        let cookie = format!("{}; HttpOnly; SameSite=Strict; path=/", session);
+        headers.insert(header::SET_COOKIE, cookie.parse().unwrap());
+        webui.session = Some(session);
+        (headers, "Ok.")
+    }
+
+    async fn set_preferences(
+        State(webui): State<Shared>,
+        headers: HeaderMap,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> StatusCode {
+        let mut webui = webui.lock().unwrap();
+        let cookie = headers.get(header::COOKIE).and_then(|c| c.to_str().ok());
+        if webui.session.is_none() || cookie != webui.session.as_deref() {
+            return StatusCode::FORBIDDEN;
+        }
+        let preferences: serde_json::Value = serde_json::from_str(&form["json"]).unwrap();
+        let port = preferences["listen_port"].as_u64().unwrap();
+        webui.ports.push(port.try_into().unwrap());
+        StatusCode::OK
+    }
+
+    /// A WebUI mock served under /qbt on 127.0.0.1, and its base URL without a
+    /// trailing slash
+    async fn webui() -> (Shared, Url) {
+        let webui = Shared::default();
+        let api = axum::Router::new()
+            .route("/api/v2/auth/login", post(login))
+            .route("/api/v2/app/setPreferences", post(set_preferences))
+            .with_state(webui.clone());
+        let app = axum::Router::new().nest("/qbt", api);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/qbt", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (webui, url.parse().unwrap())
+    }
+
+    fn credentials(password: &str) -> Option<(String, String)> {
+        Some(("admin".to_string(), password.to_string()))
+    }
+
+    #[tokio::test]
+    async fn logs_in_once() {
+        let (webui, url) = webui().await;
+        let mut qbittorrent =
+            Qbittorrent::new(url, credentials("adminadmin"), reqwest::Client::new());
+        qbittorrent.set_port(40001).await.unwrap();
+        qbittorrent.set_port(40002).await.unwrap();
+
+        let webui = webui.lock().unwrap();
+        assert_eq!(webui.logins, 1);
+        assert_eq!(webui.ports, [40001, 40002]);
+    }
+
+    #[tokio::test]
+    async fn failed_login() {
+        let (webui, url) = webui().await;
+        let mut qbittorrent = Qbittorrent::new(url, credentials("wrong"), reqwest::Client::new());
+        let error = qbittorrent.set_port(40001).await.unwrap_err();
+
+        assert!(
+            error.contains("check the username and password"),
+            "{}",
+            error
+        );
+        assert!(webui.lock().unwrap().ports.is_empty());
+    }
+
+    #[tokio::test]
+    async fn logs_in_again_when_session_expired() {
+        let (webui, url) = webui().await;
+        let mut qbittorrent =
+            Qbittorrent::new(url, credentials("adminadmin"), reqwest::Client::new());
+        qbittorrent.set_port(40001).await.unwrap();
+        // qBittorrent restarted and forgot the session
+        webui.lock().unwrap().session = None;
+        qbittorrent.set_port(40002).await.unwrap();
+
+        let webui = webui.lock().unwrap();
+        assert_eq!(webui.logins, 2);
+        assert_eq!(webui.ports, [40001, 40002]);
+    }
+
+    #[tokio::test]
+    async fn without_credentials() {
+        let (webui, url) = webui().await;
+        let mut qbittorrent = Qbittorrent::new(url, None, reqwest::Client::new());
+        let error = qbittorrent.set_port(40001).await.unwrap_err();
+
+        assert_eq!(error, "not logged in");
+        assert_eq!(webui.lock().unwrap().logins, 0);
+    }
+
+    #[test]
+    fn base_url() {
+        for (url, base) in [
+            ("http://nas:8080", "http://nas:8080/"),
+            ("http://nas/qbt", "http://nas/qbt/"),
+            ("http://nas/qbt/", "http://nas/qbt/"),
+        ] {
+            let qbittorrent = Qbittorrent::new(url.parse().unwrap(), None, reqwest::Client::new());
+            assert_eq!(qbittorrent.url.as_str(), base);
+            assert_eq!(
+                qbittorrent.endpoint("api/v2/auth/login").unwrap().as_str(),
+                format!("{}api/v2/auth/login", base)
+            );
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Finds the VPN gateway in the kernel's IPv4 routing table, for `--gateway auto`
+
+use crate::gateway::Gateway;
+use if_addrs::IfAddr;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+const ROUTE_TABLE: &str = "/proc/net/route";
+
+/// RTF_GATEWAY: the route goes through a router
+const FLAG_GATEWAY: u32 = 0x2;
+/// RTF_HOST: the route leads to a single host
+const FLAG_HOST: u32 = 0x4;
+
+/// Looks up the gateway of the default route, or with `interface` of that
+/// interface's routes, falling back to its address for WireGuard
+pub fn find_gateway(interface: Option<&str>) -> io::Result<Option<Ipv4Addr>> {
+    let table = std::fs::read_to_string(ROUTE_TABLE)?;
+    if let Some(gateway) = parse(&table, interface) {
+        return Ok(Some(gateway));
+    }
+    let Some(interface) = interface else {
+        return Ok(None);
+    };
+    let gateway = if_addrs::get_if_addrs()?
+        .into_iter()
+        .filter(|candidate| candidate.name == interface)
+        .find_map(|candidate| match candidate.addr {
+            IfAddr::V4(address) => subnet_gateway(address.ip, address.prefixlen),
+            IfAddr::V6(_) => None,
+        });
+    Ok(gateway)
+}
+
+/// WireGuard interfaces have neither a router nor a peer route, and wg-quick keeps
+/// their default route out of the main table. Their gateway is taken to be the first
+/// host of the interface's subnet, or of the surrounding /24 for the single addresses
+/// VPN providers hand out (10.2.0.1 for 10.2.0.2/32).
+fn subnet_gateway(address: Ipv4Addr, prefix_len: u8) -> Option<Ipv4Addr> {
+    let prefix_len = match prefix_len {
+        0 => return None,
+        31.. => 24,
+        prefix_len => prefix_len,
+    };
+    let mask = u32::MAX << (32 - u32::from(prefix_len));
+    let gateway = Ipv4Addr::from((u32::from(address) & mask) + 1);
+    (gateway != address).then_some(gateway)
+}
+
+/// Without an interface only the router of the default route will do, since any
+/// other router leads to some other network. On an interface the default route is
+/// preferred, then the router of any other route. Point-to-point tunnels (OpenVPN's
+/// `tun`) have no router, but a host route to the peer at the other end, which is
+/// the gateway.
+fn parse(table: &str, interface: Option<&str>) -> Option<Ipv4Addr> {
+    let mut routes: Vec<Route> = table.lines().skip(1).filter_map(Route::parse).collect();
+    if let Some(interface) = interface {
+        routes.retain(|route| route.interface == interface);
+    }
+    routes.sort_by_key(|route| route.metric);
+
+    let router =
+        |route: &&Route| route.flags & FLAG_GATEWAY != 0 && !route.gateway.is_unspecified();
+    let default = routes.iter().filter(router).find(|route| route.mask == 0);
+    let Some(interface) = interface else {
+        return default.map(|route| route.gateway);
+    };
+    default
+        .or_else(|| routes.iter().find(router))
+        .map(|route| route.gateway)
+        .or_else(|| {
+            routes
+                .iter()
+                .find(|route| {
+                    route.interface == interface
+                        && route.flags & FLAG_HOST != 0
+                        && route.mask == u32::MAX
+                })
+                .map(|route| route.destination)
+        })
+}
+
+struct Route<'a> {
+    interface: &'a str,
+    destination: Ipv4Addr,
+    gateway: Ipv4Addr,
+    flags: u32,
+    metric: u32,
+    mask: u32,
+}
+
+impl<'a> Route<'a> {
+    /// Parses a line of /proc/net/route, where addresses are hex in host byte order
+    fn parse(line: &'a str) -> Option<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 8 {
+            return None;
+        }
+        let hex = |field: &str| u32::from_str_radix(field, 16).ok();
+        let address = |field: &str| hex(field).map(|value| Ipv4Addr::from(value.to_le_bytes()));
+        Some(Route {
+            interface: fields[0],
+            destination: address(fields[1])?,
+            gateway: address(fields[2])?,
+            flags: hex(fields[3])?,
+            metric: fields[6].parse().ok()?,
+            mask: hex(fields[7])?,
+        })
+    }
+}
+
+/// Re-reads the routing table every `interval` and points the gateway at the new
+/// address when the route changed, e.g. because the VPN reconnected to another server
+pub async fn follow(gateway: Arc<Gateway>, interface: Option<String>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        match find_gateway(interface.as_deref()) {
+            Ok(Some(address)) => gateway.set_address(IpAddr::V4(address)),
+            // Routes disappear for a moment while the VPN reconnects
+            Ok(None) => debug!(
+                "No route to gateway {}, keeping {}",
+                gateway.name,
+                gateway.address()
+            ),
+            Err(e) => warn!("Failed to read {}: {}", ROUTE_TABLE, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str =
+        "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT";
+
+    fn table(routes: &[&str]) -> String {
+        std::iter::once(HEADER)
+            .chain(routes.iter().copied())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Default route via 192.168.1.1 and the LAN it is on
+    const ETH0_DEFAULT: &str = "eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0";
+    const ETH0_LAN: &str = "eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0";
+    /// OpenVPN's redirect-gateway def1: 0.0.0.0/1 and 128.0.0.0/1 via 10.8.0.1
+    const TUN0_LOW: &str = "tun0\t00000000\t0100080A\t0003\t0\t0\t0\t00000080\t0\t0\t0";
+    const TUN0_HIGH: &str = "tun0\t00000080\t0100080A\t0003\t0\t0\t0\t00000080\t0\t0\t0";
+    /// Point-to-point tunnel with a host route to the peer 10.9.0.5
+    const TUN1_PEER: &str = "tun1\t0500090A\t00000000\t0005\t0\t0\t0\tFFFFFFFF\t0\t0\t0";
+
+    #[test]
+    fn default_route() {
+        let table = table(&[ETH0_LAN, TUN0_LOW, TUN0_HIGH, ETH0_DEFAULT]);
+        assert_eq!(parse(&table, None), Some(Ipv4Addr::new(192, 168, 1, 1)));
+    }
+
+    #[test]
+    fn lowest_metric_default_route() {
+        let wg0 = "wg0\t00000000\t0100020A\t0003\t0\t0\t50\t00000000\t0\t0\t0";
+        let table = table(&[ETH0_DEFAULT, wg0]);
+        assert_eq!(parse(&table, None), Some(Ipv4Addr::new(10, 2, 0, 1)));
+    }
+
+    #[test]
+    fn no_default_route_without_interface() {
+        let table = table(&[ETH0_LAN, TUN0_LOW, TUN0_HIGH, TUN1_PEER]);
+        assert_eq!(parse(&table, None), None);
+    }
+
+    #[test]
+    fn interface_router() {
+        let table = table(&[ETH0_DEFAULT, ETH0_LAN, TUN0_LOW, TUN0_HIGH]);
+        assert_eq!(
+            parse(&table, Some("tun0")),
+            Some(Ipv4Addr::new(10, 8, 0, 1))
+        );
+        assert_eq!(
+            parse(&table, Some("eth0")),
+            Some(Ipv4Addr::new(192, 168, 1, 1))
+        );
+        assert_eq!(parse(&table, Some("wg0")), None);
+    }
+
+    #[test]
+    fn point_to_point_peer() {
+        let table = table(&[ETH0_DEFAULT, TUN1_PEER]);
+        assert_eq!(
+            parse(&table, Some("tun1")),
+            Some(Ipv4Addr::new(10, 9, 0, 5))
+        );
+    }
+
+    #[test]
+    fn wireguard_subnet() {
+        let gateway = |address: &str, prefix_len| {
+            subnet_gateway(address.parse().unwrap(), prefix_len).map(|gateway| gateway.to_string())
+        };
+        assert_eq!(gateway("10.2.0.2", 32).as_deref(), Some("10.2.0.1"));
+        assert_eq!(gateway("10.64.12.7", 16).as_deref(), Some("10.64.0.1"));
+        assert_eq!(gateway("10.8.0.6", 30).as_deref(), Some("10.8.0.5"));
+        // We are the first host ourselves
+        assert_eq!(gateway("10.2.0.1", 24), None);
+        assert_eq!(gateway("10.2.0.2", 0), None);
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        let table = table(&[
+            "eth0\t00000000",
+            "eth0\tnothex\t0101A8C0\t0003\t0\t0\t100\t00000000",
+            ETH0_DEFAULT,
+        ]);
+        assert_eq!(parse(&table, None), Some(Ipv4Addr::new(192, 168, 1, 1)));
+    }
+}
@@ -0,0 +1,96 @@
+//! Keeps known mappings in a JSON file so they survive server restarts
+
+use crate::port_file::Format;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use tokio::sync::Notify;
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    /// PCP nonce each gateway's mappings were created with, needed to renew or delete them
+    pub nonces: HashMap<String, [u8; 12]>,
+    pub mappings: Vec<SavedMapping>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedMapping {
+    pub gateway: String,
+    pub protocol: String,
+    pub internal_port: u16,
+    pub internal_address: Option<IpAddr>,
+    pub peer: Option<SocketAddr>,
+    pub external_port: u16,
+    /// Lifetime the client asked for
+    pub duration: u32,
+    pub expires_at: DateTime<Utc>,
+    pub client_address: SocketAddr,
+    pub token_id: Option<String>,
+    pub require_exact: bool,
+    pub lease: Option<SavedLease>,
+    #[serde(default)]
+    pub on_change: Option<String>,
+    #[serde(default)]
+    pub port_file: Option<String>,
+    #[serde(default)]
+    pub port_file_format: Format,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedLease {
+    /// Seconds the client may stay silent (None = until released)
+    pub keepalive: Option<u32>,
+    pub last_seen: DateTime<Utc>,
+}
+
+pub struct StateFile {
+    path: PathBuf,
+    changed: Notify,
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf) -> Self {
+        StateFile {
+            path,
+            changed: Notify::new(),
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Reads the saved state, if the file exists
+    pub fn load(&self) -> io::Result<Option<Snapshot>> {
+        let data = match std::fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writes the state through a temporary file so a crash never leaves it half written
+    pub async fn save(&self, snapshot: &Snapshot) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(snapshot)?;
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        tokio::fs::write(&temporary, data).await?;
+        tokio::fs::rename(&temporary, &self.path).await
+    }
+
+    /// Asks for the state to be written; bursts of changes end up in a single write
+    pub fn mark_changed(&self) {
+        self.changed.notify_one();
+    }
+
+    /// Waits until the state needs to be written
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+}
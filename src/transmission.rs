@@ -0,0 +1,178 @@
+//! Transmission RPC client that sets the peer port
+
+use crate::integration::PortTarget;
+use reqwest::{header::HeaderValue, StatusCode, Url};
+use serde::Deserialize;
+
+/// CSRF token Transmission hands out with a 409 and expects on every request
+const SESSION_ID: &str = "X-Transmission-Session-Id";
+
+pub struct Transmission {
+    /// The RPC endpoint, usually ending in /transmission/rpc
+    url: Url,
+    credentials: Option<(String, String)>,
+    client: reqwest::Client,
+    session_id: Option<HeaderValue>,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse {
+    result: String,
+}
+
+impl Transmission {
+    pub fn new(url: Url, credentials: Option<(String, String)>, client: reqwest::Client) -> Self {
+        Transmission {
+            url,
+            credentials,
+            client,
+            session_id: None,
+        }
+    }
+
+    async fn call(&self, body: &serde_json::Value) -> Result<reqwest::Response, String> {
+        let mut request = self.client.post(self.url.clone()).json(body);
+        if let Some((username, password)) = &self.credentials {
+            request = request.basic_auth(username, Some(password));
+        }
+        if let Some(session_id) = &self.session_id {
+            request = request.header(SESSION_ID, session_id);
+        }
+        request.send().await.map_err(|e| e.to_string())
+    }
+}
+
+impl PortTarget for Transmission {
+    fn name(&self) -> &'static str {
+        "Transmission"
+    }
+
+    async fn set_port(&mut self, port: u16) -> Result<(), String> {
+        let body = serde_json::json!({
+            "method": "session-set",
+            "arguments": { "peer-port": port },
+        });
+        let mut response = self.call(&body).await?;
+        // No session yet, or Transmission restarted: retry with the id it sent along
+        if response.status() == StatusCode::CONFLICT {
+            self.session_id = response.headers().get(SESSION_ID).cloned();
+            if self.session_id.is_none() {
+                return Err(format!("409 without an {} header", SESSION_ID));
+            }
+            response = self.call(&body).await?;
+        }
+        match response.status() {
+            StatusCode::OK => {}
+            StatusCode::UNAUTHORIZED => {
+                return Err("unauthorized, check the username and password".to_string())
+            }
+            status => return Err(format!("RPC answered {}", status)),
+        }
+        let response: RpcResponse = response.json().await.map_err(|e| e.to_string())?;
+        match response.result.as_str() {
+            "success" => Ok(()),
+            result => Err(format!("session-set failed: {}", result)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use axum::http::{header, HeaderMap};
+    use axum::response::{IntoResponse, Response};
+    use axum::Json;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    struct Rpc {
+        /// Session id Transmission currently expects
+        session_id: String,
+        conflicts: u32,
+        /// `result` of the next calls
+        result: String,
+        ports: Vec<u64>,
+    }
+
+    type Shared = Arc<Mutex<Rpc>>;
+
+    async fn rpc(
+        State(rpc): State<Shared>,
+        headers: HeaderMap,
+        Json(body): Json<serde_json::Value>,
+    ) -> Response {
+        let mut rpc = rpc.lock().unwrap();
+        // user:pass
+        let authorization = headers.get(header::AUTHORIZATION);
+        if authorization.is_none_or(|value| value != "Basic dXNlcjpwYXNz") {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        if headers
+            .get(SESSION_ID)
+            .is_none_or(|id| id != rpc.session_id.as_str())
+        {
+            rpc.conflicts += 1;
+            return (StatusCode::CONFLICT, [(SESSION_ID, rpc.session_id.clone())]).into_response();
+        }
+        assert_eq!(body["method"], "session-set");
+        rpc.ports
+            .push(body["arguments"]["peer-port"].as_u64().unwrap());
+        Json(serde_json::json!({ "result": rpc.result, "arguments": {} })).into_response()
+    }
+
+    /// A mock RPC endpoint on 127.0.0.1 and a client for it
+    async fn rpc_mock(password: &str) -> (Shared, Transmission) {
+        let rpc_state = Arc::new(Mutex::new(Rpc {
+            session_id: "session1".to_string(),
+            conflicts: 0,
+            result: "success".to_string(),
+            ports: Vec::new(),
+        }));
+        let app = axum::Router::new()
+            .route("/transmission/rpc", axum::routing::post(rpc))
+            .with_state(rpc_state.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/transmission/rpc", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let credentials = Some(("user".to_string(), password.to_string()));
+        let transmission =
+            Transmission::new(url.parse().unwrap(), credentials, reqwest::Client::new());
+        (rpc_state, transmission)
+    }
+
+    #[tokio::test]
+    async fn session_id_exchange() {
+        let (rpc, mut transmission) = rpc_mock("pass").await;
+        transmission.set_port(40001).await.unwrap();
+        transmission.set_port(40002).await.unwrap();
+        assert_eq!(rpc.lock().unwrap().conflicts, 1);
+
+        // Transmission restarted with a new session id
+        rpc.lock().unwrap().session_id = "session2".to_string();
+        transmission.set_port(40003).await.unwrap();
+
+        let rpc = rpc.lock().unwrap();
+        assert_eq!(rpc.conflicts, 2);
+        assert_eq!(rpc.ports, [40001, 40002, 40003]);
+    }
+
+    #[tokio::test]
+    async fn unauthorized() {
+        let (rpc, mut transmission) = rpc_mock("wrong").await;
+        let error = transmission.set_port(40001).await.unwrap_err();
+
+        assert!(error.starts_with("unauthorized"), "{}", error);
+        assert!(rpc.lock().unwrap().ports.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_result() {
+        let (rpc, mut transmission) = rpc_mock("pass").await;
+        rpc.lock().unwrap().result = "peer-port out of range".to_string();
+        let error = transmission.set_port(40001).await.unwrap_err();
+
+        assert_eq!(error, "session-set failed: peer-port out of range");
+    }
+}
@@ -0,0 +1,157 @@
+//! UDP exchange with the gateway shared by the NAT-PMP and PCP clients: the socket,
+//! the retransmission loop and the errors both protocols report
+
+use prometheus::IntCounter;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+/// Port the gateway listens on for NAT-PMP and PCP requests
+pub const SERVER_PORT: u16 = 5351;
+
+/// A failed request, with the protocol's result codes in `C`
+#[derive(Clone, Debug)]
+pub enum Error<C> {
+    /// Shared so that one failure can be handed to every request waiting on it
+    Io(Arc<io::Error>),
+    /// The gateway did not answer any of the retransmissions
+    Timeout,
+    /// The gateway answered with a non-zero result code
+    Gateway(C),
+}
+
+impl<C: fmt::Display> fmt::Display for Error<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Timeout => write!(f, "no response from gateway"),
+            Error::Gateway(code) => write!(f, "gateway returned {}", code),
+        }
+    }
+}
+
+impl<C: fmt::Debug + fmt::Display> std::error::Error for Error<C> {}
+
+impl<C> From<io::Error> for Error<C> {
+    fn from(e: io::Error) -> Self {
+        Error::Io(Arc::new(e))
+    }
+}
+
+/// How long to wait for a response: the timeout starts at `initial_timeout` and
+/// doubles after every attempt
+pub struct Schedule {
+    /// Protocol name for logs
+    pub protocol: &'static str,
+    pub initial_timeout: Duration,
+    pub max_attempts: u32,
+}
+
+pub struct Socket {
+    socket: UdpSocket,
+    /// Counts requests resent after a timeout
+    retransmissions: IntCounter,
+}
+
+impl Socket {
+    pub async fn connect(gateway: SocketAddr, retransmissions: IntCounter) -> io::Result<Self> {
+        let bind_address = match gateway {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(bind_address, 0)).await?;
+        // Connecting picks the source address and drops datagrams from anyone but the gateway
+        socket.connect(gateway).await?;
+        Ok(Socket {
+            socket,
+            retransmissions,
+        })
+    }
+
+    /// Source address of our requests
+    pub fn local_address(&self) -> io::Result<IpAddr> {
+        Ok(self.socket.local_addr()?.ip())
+    }
+
+    /// Sends a request on `schedule` and returns what `answer` makes of the first
+    /// datagram it does not skip: `None` skips one that does not answer the
+    /// request, and `Some(Err(_))` carries the gateway's result code
+    pub async fn request<C>(
+        &self,
+        request: &[u8],
+        schedule: &Schedule,
+        answer: impl Fn(&[u8]) -> Option<Result<Vec<u8>, C>>,
+    ) -> Result<Vec<u8>, Error<C>> {
+        let mut timeout = schedule.initial_timeout;
+        let mut buf = [0u8; 1100];
+
+        for attempt in 1..=schedule.max_attempts {
+            if attempt > 1 {
+                self.retransmissions.inc();
+            }
+            self.socket.send(request).await?;
+
+            let deadline = tokio::time::Instant::now() + timeout;
+            loop {
+                let len = match tokio::time::timeout_at(deadline, self.socket.recv(&mut buf)).await
+                {
+                    Ok(result) => result?,
+                    Err(_) => break,
+                };
+                match answer(&buf[..len]) {
+                    Some(result) => return result.map_err(Error::Gateway),
+                    None => debug!(
+                        "Ignoring unexpected {} packet ({} bytes)",
+                        schedule.protocol, len
+                    ),
+                }
+            }
+
+            debug!(
+                "No {} response after {:?} (attempt {}/{})",
+                schedule.protocol, timeout, attempt, schedule.max_attempts
+            );
+            timeout *= 2;
+        }
+
+        Err(Error::Timeout)
+    }
+}
+
+pub fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+pub fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+/// Helpers for the client tests
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub fn counter() -> IntCounter {
+        IntCounter::new("retransmissions", "test").unwrap()
+    }
+
+    /// A stand-in gateway on 127.0.0.1
+    pub async fn stand_in() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    pub async fn receive(gateway: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+        let mut buf = [0u8; 1100];
+        let (len, from) = gateway.recv_from(&mut buf).await.unwrap();
+        (buf[..len].to_vec(), from)
+    }
+}
@@ -0,0 +1,307 @@
+//! Posts port and address changes to webhook URLs, for consumers that cannot keep an
+//! /events connection open
+
+use crate::events::{Change, Event};
+use hmac::{Hmac, Mac};
+use reqwest::{header::CONTENT_TYPE, StatusCode, Url};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{debug, warn};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+pub struct Webhook {
+    url: Url,
+    /// Key for the HMAC-SHA256 signature in `X-Natpmp-Signature`
+    secret: Option<String>,
+    retries: u32,
+    /// Wait before the first retry, doubled on every further one
+    backoff: Duration,
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    pub fn new(url: Url, secret: Option<String>, retries: u32, client: reqwest::Client) -> Self {
+        Webhook {
+            url,
+            secret,
+            retries,
+            backoff: INITIAL_BACKOFF,
+            client,
+        }
+    }
+
+    /// Delivers the events one at a time and in order, until the stream ends
+    pub async fn run(self, events: impl Stream<Item = Result<Arc<Event>, u64>>) {
+        let mut events = std::pin::pin!(events);
+        while let Some(event) = events.next().await {
+            match event {
+                Ok(event) if wanted(&event.change) => self.deliver(&event).await,
+                Ok(_) => {}
+                Err(missed) => warn!(
+                    "Webhook {} fell behind and missed {} event(s)",
+                    self.url, missed
+                ),
+            }
+        }
+    }
+
+    async fn deliver(&self, event: &Event) {
+        let kind = event.change.kind();
+        let body = serde_json::to_vec(event).expect("Failed to serialize event");
+        let mut backoff = self.backoff;
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            match self.send(kind, &body).await {
+                Ok(()) => {
+                    debug!("Delivered {} to webhook {}", kind, self.url);
+                    return;
+                }
+                // The receiver rejected the payload, sending it again will not help
+                Err(e) if e.status().is_some_and(permanent) => {
+                    warn!("Webhook {} rejected {}: {}", self.url, kind, e);
+                    return;
+                }
+                Err(e) => warn!(
+                    "Failed to deliver {} to webhook {} (attempt {}/{}): {}",
+                    kind,
+                    self.url,
+                    attempt + 1,
+                    self.retries + 1,
+                    e
+                ),
+            }
+        }
+        warn!("Gave up delivering {} to webhook {}", kind, self.url);
+    }
+
+    async fn send(&self, kind: &str, body: &[u8]) -> Result<(), reqwest::Error> {
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Natpmp-Event", kind)
+            .body(body.to_vec());
+        if let Some(secret) = &self.secret {
+            request = request.header("X-Natpmp-Signature", sign(secret, body));
+        }
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Webhooks hear about the ports of managed mappings and the gateways' public addresses
+fn wanted(change: &Change) -> bool {
+    match change {
+        Change::MappingCreated(mapping) | Change::ExternalPortChanged { mapping, .. } => {
+            mapping.managed
+        }
+        Change::ExternalAddressChanged { .. } => true,
+        _ => false,
+    }
+}
+
+fn permanent(status: StatusCode) -> bool {
+    status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS
+}
+
+/// `sha256=` followed by the hex HMAC-SHA256 of the body, as GitHub signs webhooks
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::MappingDetails;
+    use axum::body::Bytes;
+    use axum::extract::State;
+    use axum::http::HeaderMap;
+    use chrono::Utc;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use tokio::net::TcpListener;
+    use tokio::time::Instant;
+
+    struct Delivery {
+        at: Instant,
+        headers: HeaderMap,
+        body: Bytes,
+    }
+
+    #[derive(Clone, Default)]
+    struct Receiver {
+        /// Statuses to answer with, in order, then 200
+        statuses: Arc<Mutex<VecDeque<StatusCode>>>,
+        deliveries: Arc<Mutex<Vec<Delivery>>>,
+    }
+
+    async fn receive(
+        State(receiver): State<Receiver>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        receiver.deliveries.lock().unwrap().push(Delivery {
+            at: Instant::now(),
+            headers,
+            body,
+        });
+        let status = receiver.statuses.lock().unwrap().pop_front();
+        status.unwrap_or(StatusCode::OK)
+    }
+
+    /// A receiver on 127.0.0.1 answering with `statuses`, and a webhook posting to it
+    async fn webhook(statuses: &[StatusCode], retries: u32) -> (Webhook, Receiver) {
+        let receiver = Receiver {
+            statuses: Arc::new(Mutex::new(statuses.iter().copied().collect())),
+            ..Default::default()
+        };
+        let app = axum::Router::new()
+            .route("/hook", axum::routing::post(receive))
+            .with_state(receiver.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut webhook = Webhook::new(
+            url.parse().unwrap(),
+            Some("secret".to_string()),
+            retries,
+            reqwest::Client::new(),
+        );
+        webhook.backoff = Duration::from_millis(20);
+        (webhook, receiver)
+    }
+
+    fn mapping(managed: bool) -> MappingDetails {
+        MappingDetails {
+            gateway: "default".to_string(),
+            via: None,
+            protocol: "tcp".to_string(),
+            internal_port: 6881,
+            internal_address: None,
+            peer: None,
+            external_port: 40001,
+            lifetime: 60,
+            expires_at: Utc::now(),
+            managed,
+        }
+    }
+
+    fn port_changed(managed: bool) -> Change {
+        Change::ExternalPortChanged {
+            mapping: mapping(managed),
+            previous_external_port: 40000,
+        }
+    }
+
+    fn event(change: Change) -> Event {
+        Event {
+            timestamp: Utc::now(),
+            change,
+        }
+    }
+
+    #[test]
+    fn signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn wanted_changes() {
+        assert!(wanted(&port_changed(true)));
+        assert!(!wanted(&port_changed(false)));
+        assert!(wanted(&Change::ExternalAddressChanged {
+            gateway: "default".to_string(),
+            external_address: "203.0.113.7".parse().unwrap(),
+            previous_external_address: None,
+        }));
+        assert!(wanted(&Change::MappingCreated(mapping(true))));
+        assert!(!wanted(&Change::MappingCreated(mapping(false))));
+        assert!(!wanted(&Change::MappingRenewed(mapping(true))));
+        assert!(!wanted(&Change::GatewayReset {
+            gateway: "default".to_string(),
+        }));
+    }
+
+    #[test]
+    fn permanent_statuses() {
+        assert!(permanent(StatusCode::BAD_REQUEST));
+        assert!(permanent(StatusCode::NOT_FOUND));
+        assert!(!permanent(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!permanent(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(!permanent(StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    #[tokio::test]
+    async fn delivers_wanted_events_signed() {
+        let (webhook, receiver) = webhook(&[], 0).await;
+        let events = [
+            event(Change::MappingRenewed(mapping(true))),
+            event(port_changed(false)),
+            event(port_changed(true)),
+        ];
+        let events = tokio_stream::iter(events.map(|event| Ok(Arc::new(event))));
+        webhook.run(events).await;
+
+        let deliveries = receiver.deliveries.lock().unwrap();
+        assert_eq!(deliveries.len(), 1);
+        let delivery = &deliveries[0];
+        assert_eq!(delivery.headers["x-natpmp-event"], "external_port_changed");
+        assert_eq!(delivery.headers[CONTENT_TYPE], "application/json");
+        assert_eq!(
+            delivery.headers["x-natpmp-signature"],
+            sign("secret", &delivery.body).as_str()
+        );
+        let body: serde_json::Value = serde_json::from_slice(&delivery.body).unwrap();
+        assert_eq!(body["type"], "external_port_changed");
+        assert_eq!(body["external_port"], 40001);
+        assert_eq!(body["previous_external_port"], 40000);
+    }
+
+    #[tokio::test]
+    async fn retries_with_backoff() {
+        let statuses = [
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::TOO_MANY_REQUESTS,
+        ];
+        let (webhook, receiver) = webhook(&statuses, 3).await;
+        webhook.deliver(&event(port_changed(true))).await;
+
+        let deliveries = receiver.deliveries.lock().unwrap();
+        assert_eq!(deliveries.len(), 3);
+        assert!(deliveries[1].at - deliveries[0].at >= Duration::from_millis(20));
+        assert!(deliveries[2].at - deliveries[1].at >= Duration::from_millis(40));
+        assert_eq!(deliveries[0].body, deliveries[2].body);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_retries() {
+        let statuses = [StatusCode::BAD_GATEWAY; 5];
+        let (webhook, receiver) = webhook(&statuses, 2).await;
+        webhook.deliver(&event(port_changed(true))).await;
+
+        assert_eq!(receiver.deliveries.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_rejected_events() {
+        let (webhook, receiver) = webhook(&[StatusCode::UNPROCESSABLE_ENTITY], 3).await;
+        webhook.deliver(&event(port_changed(true))).await;
+
+        assert_eq!(receiver.deliveries.lock().unwrap().len(), 1);
+    }
+}